@@ -0,0 +1,55 @@
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs};
+
+// Expose the git commit and the locked versions of the rustic crates to the
+// version endpoint
+fn main() {
+    // Docker builds have no .git directory, they pass the commit instead
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+        });
+    println!(
+        "cargo:rustc-env=GIT_COMMIT={}",
+        commit.as_deref().map(str::trim).unwrap_or("unknown")
+    );
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            let reference = Path::new(".git").join(reference);
+            if reference.exists() {
+                println!("cargo:rerun-if-changed={}", reference.display());
+            }
+        }
+    }
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for package in ["rustic_core", "rustic_backend"] {
+        println!(
+            "cargo:rustc-env={}_VERSION={}",
+            package.to_uppercase(),
+            locked_version(&lock, package).unwrap_or("unknown")
+        );
+    }
+}
+
+// Version of a package in Cargo.lock, the line after its name
+fn locked_version<'a>(lock: &'a str, package: &str) -> Option<&'a str> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+}
@@ -0,0 +1,110 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use rustic_core::repofile::SnapshotFile;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::exporter::Exporter;
+
+/// Backups selected by the `repo` and `exclude` query parameters
+#[derive(Deserialize, Default)]
+pub(crate) struct RepoFilter {
+    /// Comma separated names of the only backups to select
+    repo: Option<String>,
+    /// Comma separated names of backups not to select
+    exclude: Option<String>,
+}
+
+impl RepoFilter {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.repo.is_none() && self.exclude.is_none()
+    }
+
+    pub(crate) fn includes(&self, name: &str) -> bool {
+        let listed = |list: &str| list.split(',').any(|listed| listed.trim() == name);
+        self.repo.as_deref().is_none_or(listed) && !self.exclude.as_deref().is_some_and(listed)
+    }
+}
+
+/// Cached snapshots of the selected backups, oldest first
+pub(crate) async fn snapshots_handler(
+    State(exporter): State<Arc<Exporter>>,
+    Query(filter): Query<RepoFilter>,
+) -> impl IntoResponse {
+    let mut snapshots: Vec<(String, SnapshotFile)> = exporter
+        .snapshots(|name| filter.includes(name))
+        .into_iter()
+        .flat_map(|(name, snapshots)| snapshots.into_iter().map(move |s| (name.clone(), s)))
+        .collect();
+    snapshots.sort_by_key(|(_, snapshot)| snapshot.time);
+    let snapshots: Vec<Value> = snapshots
+        .iter()
+        .map(|(name, snapshot)| snapshot_json(name, snapshot))
+        .collect();
+    Json(json!({ "snapshots": snapshots }))
+}
+
+/// Status of the selected repositories, for healthchecks needing more than
+/// the readiness
+pub(crate) async fn repositories_handler(
+    State(exporter): State<Arc<Exporter>>,
+    Query(filter): Query<RepoFilter>,
+) -> impl IntoResponse {
+    let repositories: Vec<Value> = exporter
+        .statuses()
+        .into_iter()
+        .filter(|(name, _)| filter.includes(name))
+        .map(|(name, status)| {
+            // failures to open the repository fail the cycle and are retried
+            let state = if !status.running {
+                "stopped"
+            } else if status.failing {
+                "error"
+            } else if status.ready {
+                "ready"
+            } else {
+                "opening"
+            };
+            json!({
+                "name": name,
+                "state": state,
+                "last_collection": status.last_success.map(|time| time.to_rfc3339()),
+                "snapshots": status.snapshots,
+                "last_error": status.last_error,
+            })
+        })
+        .collect();
+    Json(json!({ "repositories": repositories }))
+}
+
+fn snapshot_json(repo_name: &str, snapshot: &SnapshotFile) -> Value {
+    let summary = snapshot.summary.as_ref().map(|summary| {
+        json!({
+            "command": summary.command,
+            "backup_start": summary.backup_start.to_rfc3339(),
+            "backup_end": summary.backup_end.to_rfc3339(),
+            "backup_duration": summary.backup_duration,
+            "total_duration": summary.total_duration,
+            "files_new": summary.files_new,
+            "total_files_processed": summary.total_files_processed,
+            "total_bytes_processed": summary.total_bytes_processed,
+            "data_added": summary.data_added,
+        })
+    });
+    json!({
+        "repo_name": repo_name,
+        "id": snapshot.id.to_string(),
+        "time": snapshot.time.to_rfc3339(),
+        "hostname": snapshot.hostname,
+        "username": snapshot.username,
+        "label": snapshot.label,
+        "paths": snapshot.paths.iter().collect::<Vec<_>>(),
+        "tags": snapshot.tags.iter().collect::<Vec<_>>(),
+        "program_version": snapshot.program_version,
+        "summary": summary,
+    })
+}
@@ -0,0 +1,133 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::{env, fs};
+use tracing::warn;
+
+use crate::server::WebConfig;
+
+const BEARER_TOKEN_ENV: &str = "RUSTIC_EXPORTER_BEARER_TOKEN";
+
+/// Checks the credentials of requests against the users and the bearer
+/// token of the web configuration
+#[derive(Debug)]
+pub(crate) struct Authenticator {
+    /// bcrypt hash of the password of each user
+    users: HashMap<String, String>,
+    bearer_token: Option<String>,
+    // bcrypt is slow on purpose, so accepted headers are remembered
+    accepted: Mutex<HashSet<String>>,
+}
+
+impl Authenticator {
+    /// Authenticator of the web configuration, `None` without users nor
+    /// bearer token
+    pub(crate) fn new(web: &WebConfig) -> Result<Option<Self>, String> {
+        let bearer_token = match &web.bearer_token_file {
+            Some(file) => Some(
+                fs::read_to_string(file)
+                    .map(|token| token.trim().to_string())
+                    .map_err(|e| format!("Unable to read the bearer token {}: {}", file, e))?,
+            ),
+            None => env::var(BEARER_TOKEN_ENV).ok(),
+        };
+        if bearer_token.as_ref().is_some_and(|token| token.is_empty()) {
+            return Err("The bearer token is empty".to_string());
+        }
+        if web.basic_auth_users.is_empty() && bearer_token.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            users: web.basic_auth_users.clone(),
+            bearer_token,
+            accepted: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    async fn verify(&self, authorization: &str) -> bool {
+        if let (Some(token), Some(given)) =
+            (&self.bearer_token, authorization.strip_prefix("Bearer "))
+        {
+            return constant_time_eq(token.as_bytes(), given.as_bytes());
+        }
+        if self.accepted.lock().unwrap().contains(authorization) {
+            return true;
+        }
+        let Some((user, password)) = authorization
+            .strip_prefix("Basic ")
+            .and_then(|credentials| STANDARD.decode(credentials).ok())
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .and_then(|credentials| {
+                let (user, password) = credentials.split_once(':')?;
+                Some((user.to_string(), password.to_string()))
+            })
+        else {
+            return false;
+        };
+        let Some(hash) = self.users.get(&user).cloned() else {
+            return false;
+        };
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|verified| verified.map_err(|e| e.to_string()));
+        match verified {
+            Ok(true) => {
+                self.accepted
+                    .lock()
+                    .unwrap()
+                    .insert(authorization.to_string());
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("Unable to verify the password of user {}: {}", user, e);
+                false
+            }
+        }
+    }
+}
+
+/// Middleware rejecting requests without valid credentials
+pub(crate) async fn authenticate(
+    State(authenticator): State<Arc<Authenticator>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // CORS preflight requests of browsers are sent without credentials
+    if request.method() == Method::OPTIONS {
+        return next.run(request).await;
+    }
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match authorization {
+        Some(authorization) if authenticator.verify(authorization).await => next.run(request).await,
+        _ => {
+            let challenge = if authenticator.users.is_empty() {
+                "Bearer realm=\"rustic-exporter\""
+            } else {
+                "Basic realm=\"rustic-exporter\""
+            };
+            let mut response = StatusCode::UNAUTHORIZED.into_response();
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(challenge),
+            );
+            response
+        }
+    }
+}
+
+// Compare without returning early, so the time taken does not reveal how
+// much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,87 @@
+use aws_config::{BehaviorVersion, SdkConfig};
+use serde_json::Value;
+
+use crate::config::Backup;
+
+/// Prefix of backend option values read from the SSM parameter store, like
+/// `ssm://backup/s3/secret_access_key`
+const SSM_PREFIX: &str = "ssm://";
+
+impl Backup {
+    /// Fetch the password from AWS Secrets Manager and the `ssm://` backend
+    /// options from the SSM parameter store, with the credentials of the
+    /// default AWS chain
+    pub(crate) async fn apply_aws_secrets(&mut self) -> Result<(), String> {
+        let references = self
+            .options
+            .values()
+            .chain(self.options_hot.values())
+            .chain(self.options_cold.values())
+            .any(|value| value.starts_with(SSM_PREFIX));
+        if self.password_aws_secret.is_none() && !references {
+            return Ok(());
+        }
+
+        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        if let Some(secret) = &self.password_aws_secret {
+            self.password = Some(secret_value(&config, secret).await?);
+        }
+        if references {
+            let client = aws_sdk_ssm::Client::new(&config);
+            for options in [
+                &mut self.options,
+                &mut self.options_hot,
+                &mut self.options_cold,
+            ] {
+                for value in options.values_mut() {
+                    let Some(name) = value.strip_prefix(SSM_PREFIX) else {
+                        continue;
+                    };
+                    *value = parameter_value(&client, &format!("/{}", name)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Secret string of a secret, or the key of a JSON secret given as `id#key`
+async fn secret_value(config: &SdkConfig, secret: &str) -> Result<String, String> {
+    let (id, key) = match secret.rsplit_once('#') {
+        Some((id, key)) => (id, Some(key)),
+        None => (secret, None),
+    };
+    let output = aws_sdk_secretsmanager::Client::new(config)
+        .get_secret_value()
+        .secret_id(id)
+        .send()
+        .await
+        .map_err(|e| format!("Unable to read AWS secret {}: {}", id, e))?;
+    let value = output
+        .secret_string()
+        .ok_or_else(|| format!("AWS secret {} has no secret string", id))?;
+    let Some(key) = key else {
+        return Ok(value.to_string());
+    };
+    let json: Value = serde_json::from_str(value)
+        .map_err(|e| format!("AWS secret {} is not a JSON object: {}", id, e))?;
+    json[key]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("No key {} in AWS secret {}", key, id))
+}
+
+async fn parameter_value(client: &aws_sdk_ssm::Client, name: &str) -> Result<String, String> {
+    let output = client
+        .get_parameter()
+        .name(name)
+        .with_decryption(true)
+        .send()
+        .await
+        .map_err(|e| format!("Unable to read SSM parameter {}: {}", name, e))?;
+    output
+        .parameter()
+        .and_then(|p| p.value())
+        .map(str::to_string)
+        .ok_or_else(|| format!("SSM parameter {} has no value", name))
+}
@@ -0,0 +1,269 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::config::Backup;
+
+/// S3 compatible storage through opendal
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3Backend {
+    bucket: String,
+    root: Option<String>,
+    endpoint: Option<String>,
+    region: Option<String>,
+    access_key_id: Option<String>,
+    access_key_id_file: Option<String>,
+    secret_access_key: Option<String>,
+    secret_access_key_file: Option<String>,
+    session_token: Option<String>,
+    session_token_file: Option<String>,
+    enable_virtual_host_style: Option<bool>,
+}
+
+/// SFTP server through opendal
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SftpBackend {
+    endpoint: String,
+    root: Option<String>,
+    user: Option<String>,
+    /// Path of the private key
+    key: Option<String>,
+    known_hosts_strategy: Option<String>,
+}
+
+/// rustic or restic REST server
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RestBackend {
+    url: String,
+    /// PEM file of a CA certificate trusted in addition to the system ones
+    cacert: Option<String>,
+    /// PEM file of the client certificate and its private key
+    client_cert: Option<String>,
+    /// Not supported by the rustic REST backend, which reads the key from
+    /// `client_cert`
+    client_key: Option<String>,
+    /// Not supported by the rustic REST backend, which always verifies the
+    /// server certificate
+    insecure_skip_verify: Option<bool>,
+}
+
+/// Options rustic handles for every opendal service
+const OPENDAL_OPTIONS: &[&str] = &["retry", "connections", "throttle"];
+const S3_OPTIONS: &[&str] = &[
+    "bucket",
+    "root",
+    "endpoint",
+    "region",
+    "access_key_id",
+    "secret_access_key",
+    "session_token",
+    "enable_virtual_host_style",
+];
+const SFTP_OPTIONS: &[&str] = &["endpoint", "root", "user", "key", "known_hosts_strategy"];
+const REST_OPTIONS: &[&str] = &["cacert", "tls-client-cert", "retry", "timeout"];
+
+// Options of the backends of the typed sections, `None` for other backends
+fn known_options(repository: &str) -> Option<Vec<&'static str>> {
+    if repository.starts_with("rest:") {
+        return Some(REST_OPTIONS.to_vec());
+    }
+    let service = match repository.strip_prefix("opendal:")? {
+        "s3" => S3_OPTIONS,
+        "sftp" => SFTP_OPTIONS,
+        _ => return None,
+    };
+    Some([service, OPENDAL_OPTIONS].concat())
+}
+
+// Collect the set fields of a typed section as backend options
+struct Options(HashMap<String, String>);
+
+impl Options {
+    fn set(&mut self, key: &str, value: &Option<impl ToString>) -> &mut Self {
+        if let Some(value) = value {
+            self.0.insert(key.to_string(), value.to_string());
+        }
+        self
+    }
+}
+
+impl S3Backend {
+    fn translate(&self) -> (String, HashMap<String, String>) {
+        let mut options = Options(HashMap::new());
+        options
+            .set("bucket", &Some(&self.bucket))
+            .set("root", &self.root)
+            .set("endpoint", &self.endpoint)
+            .set("region", &self.region)
+            .set("access_key_id", &self.access_key_id)
+            .set("access_key_id_file", &self.access_key_id_file)
+            .set("secret_access_key", &self.secret_access_key)
+            .set("secret_access_key_file", &self.secret_access_key_file)
+            .set("session_token", &self.session_token)
+            .set("session_token_file", &self.session_token_file)
+            .set("enable_virtual_host_style", &self.enable_virtual_host_style);
+        ("opendal:s3".to_string(), options.0)
+    }
+}
+
+impl SftpBackend {
+    fn translate(&self) -> (String, HashMap<String, String>) {
+        let mut options = Options(HashMap::new());
+        options
+            .set("endpoint", &Some(&self.endpoint))
+            .set("root", &self.root)
+            .set("user", &self.user)
+            .set("key", &self.key)
+            .set("known_hosts_strategy", &self.known_hosts_strategy);
+        ("opendal:sftp".to_string(), options.0)
+    }
+}
+
+impl RestBackend {
+    fn translate(&self) -> Result<(String, HashMap<String, String>), String> {
+        if self.client_key.is_some() {
+            return Err(
+                "client_key is not supported, put the private key in the client_cert PEM file"
+                    .to_string(),
+            );
+        }
+        if self.insecure_skip_verify == Some(true) {
+            return Err(
+                "insecure_skip_verify is not supported by the REST backend, trust the server CA with cacert"
+                    .to_string(),
+            );
+        }
+        let mut options = Options(HashMap::new());
+        options
+            .set("cacert", &self.cacert)
+            .set("tls-client-cert", &self.client_cert);
+        Ok((format!("rest:{}", self.url), options.0))
+    }
+}
+
+impl Backup {
+    /// Backend options unknown to the backends of the repositories, only
+    /// checked for the backends of the typed sections
+    pub(crate) fn unknown_backend_options(&self) -> Vec<String> {
+        let cold = known_options(&self.repository);
+        let hot = self.repo_hot.as_deref().and_then(known_options);
+        let mut unknown = Vec::new();
+        for (options, known) in [
+            (&self.options, [&cold, &hot]),
+            (&self.options_cold, [&cold, &None]),
+            (&self.options_hot, [&hot, &None]),
+        ] {
+            for key in options.keys() {
+                // options read from files are named with a suffix
+                let name = key
+                    .strip_suffix("_file")
+                    .or_else(|| key.strip_suffix("-file"))
+                    .unwrap_or(key);
+                if known
+                    .iter()
+                    .copied()
+                    .flatten()
+                    .any(|known| !known.contains(&name))
+                {
+                    unknown.push(key.clone());
+                }
+            }
+        }
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
+
+    /// Turn the typed backend section of the backup into its repository and
+    /// backend options
+    pub(crate) fn apply_backend(&mut self) -> Result<(), String> {
+        let rest = self
+            .rest
+            .as_ref()
+            .map(RestBackend::translate)
+            .transpose()
+            .map_err(|e| format!("{}, backup: {}", e, self.name))?;
+        let sections = [
+            self.s3.as_ref().map(S3Backend::translate),
+            self.sftp.as_ref().map(SftpBackend::translate),
+            rest,
+        ];
+        let mut sections = sections.into_iter().flatten();
+        let Some((repository, options)) = sections.next() else {
+            return Ok(());
+        };
+        if sections.next().is_some() {
+            return Err(format!(
+                "Only one backend section is allowed, backup: {}",
+                self.name
+            ));
+        }
+        if !self.repository.is_empty() {
+            return Err(format!(
+                "repository and a backend section are exclusive, backup: {}",
+                self.name
+            ));
+        }
+
+        self.repository = repository;
+        for (key, value) in options {
+            if self.options.contains_key(&key) {
+                return Err(format!(
+                    "Option {} is set in options and in the backend section, backup: {}",
+                    key, self.name
+                ));
+            }
+            self.options.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rest(toml: &str) -> Result<(String, HashMap<String, String>), String> {
+        toml::from_str::<RestBackend>(toml).unwrap().translate()
+    }
+
+    #[test]
+    fn rest_options() {
+        let (repository, options) = rest(
+            "url = \"https://rest.internal:8000/backup\"\ncacert = \"/ca.pem\"\nclient_cert = \"/client.pem\"",
+        )
+        .unwrap();
+        assert_eq!(repository, "rest:https://rest.internal:8000/backup");
+        assert_eq!(
+            options,
+            HashMap::from([
+                ("cacert".to_string(), "/ca.pem".to_string()),
+                ("tls-client-cert".to_string(), "/client.pem".to_string()),
+            ])
+        );
+        let (_, options) =
+            rest("url = \"https://rest.internal\"\ninsecure_skip_verify = false").unwrap();
+        assert!(options.is_empty());
+    }
+
+    #[test]
+    fn unknown_options_of_typed_backends() {
+        let backup: Backup = toml::from_str(
+            "name = \"a\"\nrepository = \"opendal:s3\"\n[options]\nbucket = \"b\"\nsecret_access_key_file = \"/key\"\nretry = \"3\"\nbukcet = \"b\"",
+        )
+        .unwrap();
+        assert_eq!(backup.unknown_backend_options(), vec!["bukcet".to_string()]);
+        let backup: Backup =
+            toml::from_str("name = \"a\"\nrepository = \"/srv/backup\"\n[options]\nany = \"1\"")
+                .unwrap();
+        assert!(backup.unknown_backend_options().is_empty());
+    }
+
+    #[test]
+    fn rest_unsupported_options() {
+        assert!(rest("url = \"https://rest.internal\"\nclient_key = \"/key.pem\"").is_err());
+        assert!(rest("url = \"https://rest.internal\"\ninsecure_skip_verify = true").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use crate::config::ConfigSource;
+
+/// Validate the configuration without opening the repositories nor building
+/// their backends. Returns the number of backups, or the description of
+/// every problem found.
+pub(crate) fn check_config(source: &ConfigSource) -> Result<usize, Vec<String>> {
+    // unresolved placeholders are errors here
+    let source = ConfigSource {
+        strict_env: true,
+        ..source.clone()
+    };
+    let (config, unknown, invalid) = source.load_checked().map_err(|e| vec![e])?;
+
+    let mut problems: Vec<String> = unknown
+        .into_iter()
+        .map(|field| format!("Unknown field {}", field))
+        .chain(invalid)
+        .collect();
+    for backup in &config.backups {
+        // passwords of secret stores are only fetched when the repository opens
+        if let Err(e) = backup.repository_options() {
+            if !backup.password_from_secret_store() {
+                problems.push(format!("backup {}: {}", backup.name, e));
+            }
+        }
+        if let Err(e) = backup.backend_options() {
+            problems.push(format!("backup {}: {}", backup.name, e));
+        }
+        for option in backup.unknown_backend_options() {
+            problems.push(format!(
+                "backup {}: Unknown backend option {}",
+                backup.name, option
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(config.backups.len())
+    } else {
+        Err(problems)
+    }
+}
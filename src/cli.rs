@@ -1,9 +1,16 @@
-use clap::Parser;
+use crate::config::ConfigFormat;
+use crate::server::ListenAddr;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use std::time::Duration;
 
 /// Rustic exporter
 #[derive(Parser)]
 #[command(version, about)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
     /// Metrics collection frequency in seconds
     #[arg(long, short, value_name = "INTERVAL", default_value = "300")]
     pub(crate) interval: u64,
@@ -16,9 +23,29 @@ pub(crate) struct Args {
     #[arg(long, short, value_name = "VERBOSE")]
     pub(crate) verbose: bool,
 
-    /// Path to the configuration file
-    #[arg(long, short, long = "config", value_name = "CONFIG")]
-    pub(crate) config_path: String,
+    /// Path to the configuration file or a directory of them, read from environment variables if omitted
+    #[arg(long, short, long = "config", value_name = "CONFIG", global = true)]
+    pub(crate) config_path: Option<String>,
+
+    /// Format of the configuration file, detected by file extension if omitted
+    #[arg(long, value_name = "CONFIG_FORMAT", global = true)]
+    pub(crate) config_format: Option<ConfigFormat>,
+
+    /// Fail if the configuration file references unset environment variables
+    #[arg(long, value_name = "STRICT_ENV")]
+    pub(crate) strict_env: bool,
+
+    /// Watch the configuration file and reload it on changes
+    #[arg(long, value_name = "WATCH_CONFIG")]
+    pub(crate) watch_config: bool,
+
+    /// Log every request with its method, path, status, duration and peer
+    #[arg(long, value_name = "ACCESS_LOG")]
+    pub(crate) access_log: bool,
+
+    /// Prefix of the metric names, overrides the configuration [default: rustic]
+    #[arg(long, value_name = "METRIC_PREFIX", value_parser = crate::config::parse_metric_prefix)]
+    pub(crate) metric_prefix: Option<String>,
 
     /// Server host
     #[arg(long, value_name = "HOST", default_value = "0.0.0.0")]
@@ -27,4 +54,67 @@ pub(crate) struct Args {
     /// Server port
     #[arg(long, value_name = "PORT", default_value = "8080")]
     pub(crate) port: u16,
+
+    /// Address to listen on instead of --host and --port, host:port or
+    /// unix:<path> for a Unix domain socket. Repeat it to listen on several
+    /// addresses.
+    #[arg(long, value_name = "LISTEN")]
+    pub(crate) listen: Vec<ListenAddr>,
+
+    /// Time to wait on shutdown for the running requests and collections
+    #[arg(long, value_name = "SHUTDOWN_TIMEOUT", default_value = "30s", value_parser = humantime::parse_duration)]
+    pub(crate) shutdown_timeout: Duration,
+
+    /// Maximum number of concurrent scrapes of /metrics, 0 for no limit
+    #[arg(long, value_name = "MAX_REQUESTS", default_value = "40")]
+    pub(crate) max_requests: usize,
+
+    /// Maximum number of repositories opened at once, 0 for no limit
+    #[arg(long, value_name = "MAX_CONCURRENT_OPENS", default_value = "8")]
+    pub(crate) max_concurrent_opens: usize,
+
+    /// Maximum number of repositories running blocking operations at once,
+    /// 0 for no limit
+    #[arg(long, value_name = "MAX_CONCURRENT_COLLECTIONS", default_value = "4")]
+    pub(crate) max_concurrent_collections: usize,
+
+    /// Enable reloading the configuration with POST /-/reload
+    #[arg(long = "web.enable-lifecycle", value_name = "WEB_ENABLE_LIFECYCLE")]
+    pub(crate) enable_lifecycle: bool,
+
+    /// When /readyz reports ready: once all repositories are open, once any
+    /// is open, or immediately
+    #[arg(long, value_name = "READINESS", value_enum, default_value_t = Readiness::All)]
+    pub(crate) readiness: Readiness,
+
+    /// Web configuration file of the Prometheus exporter-toolkit with the
+    /// TLS and basic auth settings, replaces the [web] section
+    #[arg(long = "web.config.file", value_name = "WEB_CONFIG_FILE")]
+    pub(crate) web_config_file: Option<String>,
+
+    /// PEM file of the server certificate chain, serves HTTPS with --tls-key
+    #[arg(long, value_name = "TLS_CERT")]
+    pub(crate) tls_cert: Option<String>,
+
+    /// PEM file of the private key of the server certificate
+    #[arg(long, value_name = "TLS_KEY")]
+    pub(crate) tls_key: Option<String>,
+
+    /// PEM file of the CAs verifying required client certificates
+    #[arg(long, value_name = "TLS_CLIENT_CA")]
+    pub(crate) tls_client_ca: Option<String>,
+}
+
+/// Readiness policy of /readyz
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Readiness {
+    All,
+    Any,
+    Immediately,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Validate the configuration and exit
+    CheckConfig,
 }
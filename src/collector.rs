@@ -1,138 +1,1202 @@
-use crate::config::Backup;
+use crate::config::{Backup, CollectionMode, MetricSelection, SnapshotInfoLabel};
+use crate::error::{CollectionError, ErrorKind};
+use crate::index;
+use crate::relabel::{Labels, Series, SeriesOptions};
 
+use chrono::{DateTime, Local};
 use prometheus_client::{
     collector::Collector,
-    encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric},
-    metrics::{family::Family, gauge::Gauge},
+    encoding::{DescriptorEncoder, EncodeMetric},
+    metrics::{counter::Counter, gauge::Gauge, histogram::Histogram},
 };
-use rustic_backend::BackendOptions;
+use rand::Rng;
 use rustic_core::{
-    repofile::SnapshotFile, NoProgressBars, OpenStatus, Repository, RepositoryOptions,
+    repofile::{ConfigFile, SnapshotFile, SnapshotSummary},
+    NoProgressBars, OpenStatus, Repository, SnapshotGroupCriterion,
 };
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{atomic::AtomicU64, Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
 use tracing::{debug, error, info, warn};
 
+const SHORT_ID_LENGTH: usize = 8;
+/// First delay before opening a repository again
+const OPEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Default)]
 struct State {
     ready: bool,
+    collection_success: bool,
+    collection_duration: f64,
+    collection_errors: HashMap<ErrorKind, u64>,
     repository: Option<Repository<NoProgressBars, OpenStatus>>,
+    // kept while the repository is closed between cycles
+    repository_config: Option<ConfigFile>,
+    collected: Arc<Collected>,
+    series_dropped: u64,
+    // generation whose dropped series are counted, they are counted once
+    // however many times it is scraped
+    series_dropped_generation: Option<u64>,
+    task: Option<AbortHandle>,
+    // blocking operation still running after its timeout
+    hung: Option<AbortHandle>,
+    collecting: bool,
+    cycle_start: Option<DateTime<Local>>,
+    cycle_end: Option<DateTime<Local>>,
+    next_cycle: Option<DateTime<Local>>,
+    last_error: Option<String>,
+    // snapshots which disappeared from the repository between cycles
+    snapshots_removed: u64,
+    // end of the last collection, successful or not
+    last_collection: Option<DateTime<Local>>,
+    last_success: Option<DateTime<Local>>,
+    open_attempts: u64,
+    // consecutive failures to open the repository
+    open_failures: u32,
+    // number of finished cycles, the metrics only change with a cycle
+    generation: u64,
+}
+
+// Data of the last successful collection. A collection builds a new one
+// and swaps it in, scrapes never wait for the backend.
+#[derive(Debug, Default)]
+struct Collected {
     snapshots: Vec<SnapshotFile>,
+    forget: Option<ForgetSummary>,
+    pack_sizes: Option<Histogram>,
+}
+
+// Copy of the state encoded by a scrape, so the series are built without
+// holding the state lock
+struct Exported {
+    ready: bool,
+    collection_success: bool,
+    collection_duration: f64,
+    collection_errors: HashMap<ErrorKind, u64>,
+    open_attempts: u64,
+    open_failures: u32,
+    last_success: Option<DateTime<Local>>,
+    snapshots_removed: u64,
+    repository_config: Option<ConfigFile>,
+    collected: Arc<Collected>,
+    generation: u64,
+}
+
+impl State {
+    fn exported(&self) -> Exported {
+        Exported {
+            ready: self.ready,
+            collection_success: self.collection_success,
+            collection_duration: self.collection_duration,
+            collection_errors: self.collection_errors.clone(),
+            open_attempts: self.open_attempts,
+            open_failures: self.open_failures,
+            last_success: self.last_success,
+            snapshots_removed: self.snapshots_removed,
+            repository_config: self.repository_config.clone(),
+            collected: self.collected.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+/// State of the background collection of a repository, for debugging
+#[derive(Debug)]
+pub struct CollectorStatus {
+    pub ready: bool,
+    /// Whether the background task is running. Failures to open the
+    /// repository are retried, so it only ends once the collector is
+    /// stopped.
+    pub running: bool,
+    pub collecting: bool,
+    pub cycle_start: Option<DateTime<Local>>,
+    pub cycle_end: Option<DateTime<Local>>,
+    pub next_cycle: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+    /// End of the last successful collection
+    pub last_success: Option<DateTime<Local>>,
+    /// Whether the last collection failed
+    pub failing: bool,
+    pub snapshots: usize,
+}
+
+// Result of a forget dry-run with the configured keep policy
+#[derive(Debug, Default)]
+struct ForgetSummary {
+    to_forget: usize,
+    kept_by_reason: BTreeMap<String, usize>,
 }
 
 #[derive(Clone, Debug)]
 pub struct RusticCollector {
     backup: Backup,
     interval: u64,
+    selection: Arc<MetricSelection>,
     state: Arc<Mutex<State>>,
+    // held while the repository is opened or collected, so a shutdown can
+    // wait for the running cycle
+    cycle: Arc<tokio::sync::Mutex<()>>,
+    // wakes the background task for a cycle before the interval is over
+    refresh: Arc<Notify>,
+    limits: Limits,
+}
+
+/// Limits of the repository operations shared by all collectors
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Repositories opened at once
+    opens: Arc<Semaphore>,
+    /// Repositories running blocking operations at once
+    collections: Arc<Semaphore>,
+}
+
+impl Limits {
+    /// Limits of `max_opens` repositories opened and `max_collections`
+    /// collected at once, 0 for no limit
+    pub fn new(max_opens: usize, max_collections: usize) -> Self {
+        let semaphore = |permits| {
+            Arc::new(Semaphore::new(match permits {
+                0 => Semaphore::MAX_PERMITS,
+                permits => permits,
+            }))
+        };
+        Self {
+            opens: semaphore(max_opens),
+            collections: semaphore(max_collections),
+        }
+    }
+}
+
+// Label set of a family, turned into a plain label list which relabel rules
+// are applied to
+macro_rules! label_set {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        #[derive(Clone, Debug, Default)]
+        struct $name {
+            $($field: String,)*
+        }
+
+        impl From<$name> for Labels {
+            fn from(labels: $name) -> Self {
+                vec![$((stringify!($field).to_string(), labels.$field)),*]
+            }
+        }
+    };
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Default)]
-struct RepositoryInfoLabels {
-    repo_name: String,
-    repo_id: String,
-    version: String,
+label_set! {
+    RepositoryLabels {
+        repo_name,
+    }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Default)]
-struct SnapshotInfoLabels {
-    repo_name: String,
-    repo_id: String,
-    snapshot_id: String,
-    paths: String,
-    hostname: String,
-    username: String,
-    tags: String,
-    program_version: String,
+label_set! {
+    CollectionErrorLabels {
+        repo_name,
+        kind,
+    }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Default)]
-struct SnapshotLabels {
-    repo_name: String,
-    repo_id: String,
-    snapshot_id: String,
+label_set! {
+    RepositoryIdLabels {
+        repo_name,
+        repo_id,
+    }
+}
+
+label_set! {
+    KeepReasonLabels {
+        repo_name,
+        repo_id,
+        reason,
+    }
+}
+
+label_set! {
+    RepositoryInfoLabels {
+        repo_name,
+        repo_id,
+        version,
+    }
+}
+
+label_set! {
+    HostLabels {
+        repo_name,
+        repo_id,
+        hostname,
+    }
+}
+
+label_set! {
+    PathLabels {
+        repo_name,
+        repo_id,
+        paths,
+    }
+}
+
+label_set! {
+    SnapshotGroupLabels {
+        repo_name,
+        repo_id,
+        hostname,
+        snapshot_label,
+        paths,
+    }
+}
+
+label_set! {
+    SnapshotCommandLabels {
+        repo_name,
+        repo_id,
+        snapshot_id,
+        command,
+    }
+}
+
+label_set! {
+    SnapshotOriginalLabels {
+        repo_name,
+        repo_id,
+        snapshot_id,
+        original_id,
+    }
+}
+
+label_set! {
+    SnapshotPathLabels {
+        repo_name,
+        repo_id,
+        snapshot_id,
+        path,
+    }
+}
+
+label_set! {
+    SnapshotTagLabels {
+        repo_name,
+        repo_id,
+        snapshot_id,
+        tag,
+    }
+}
+
+label_set! {
+    SnapshotLabels {
+        repo_name,
+        repo_id,
+        snapshot_id,
+    }
 }
 
 struct Metrics {
-    rustic_repository_info: Family<RepositoryInfoLabels, Gauge>,
-    rustic_snapshot_info: Family<SnapshotInfoLabels, Gauge>,
-    rustic_snapshot_timestamp: Family<SnapshotLabels, Gauge<f64, AtomicU64>>,
-    rustic_snapshot_backup_start_timestamp: Family<SnapshotLabels, Gauge<f64, AtomicU64>>,
-    rustic_snapshot_backup_end_timestamp: Family<SnapshotLabels, Gauge<f64, AtomicU64>>,
-    rustic_snpashot_backup_duration_seconds: Family<SnapshotLabels, Gauge<f64, AtomicU64>>,
-    rustic_snapshot_files_total: Family<SnapshotLabels, Gauge>,
-    rustic_snapshot_size_bytes: Family<SnapshotLabels, Gauge>,
+    rustic_backup_enabled: Series<Gauge>,
+    rustic_collection_success: Series<Gauge>,
+    rustic_repository_ready: Series<Gauge>,
+    rustic_repository_open_attempts: Series<Counter>,
+    rustic_repository_open_failures: Series<Gauge>,
+    rustic_collection_duration_seconds: Series<Gauge<f64, AtomicU64>>,
+    rustic_collection_last_success_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_collection_staleness_seconds: Series<Gauge<f64, AtomicU64>>,
+    rustic_collection_errors: Series<Counter>,
+    rustic_repository_info: Series<Gauge>,
+    rustic_repository_version: Series<Gauge>,
+    rustic_repository_chunker_polynomial_present: Series<Gauge>,
+    rustic_repository_compression_enabled: Series<Gauge>,
+    rustic_repository_compression_level: Series<Gauge>,
+    rustic_repository_treepack_size_bytes: Series<Gauge>,
+    rustic_repository_datapack_size_bytes: Series<Gauge>,
+    rustic_repository_hosts: Series<Gauge>,
+    rustic_oldest_snapshot_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_backup_on_schedule: Series<Gauge>,
+    rustic_backup_overdue_seconds: Series<Gauge<f64, AtomicU64>>,
+    rustic_snapshots_to_forget: Series<Gauge>,
+    rustic_snapshots_removed: Series<Counter>,
+    rustic_snapshots_without_summary: Series<Gauge>,
+    rustic_snapshots_kept_by_policy: Series<Gauge>,
+    // labels are configurable, so they are built as a list
+    rustic_snapshot_info: Series<Gauge>,
+    rustic_snapshot_path_info: Series<Gauge>,
+    rustic_snapshot_tag_info: Series<Gauge>,
+    rustic_snapshot_command_info: Series<Gauge>,
+    rustic_snapshot_original_info: Series<Gauge>,
+    rustic_snapshots_by_host: Series<Gauge>,
+    rustic_snapshots_by_path: Series<Gauge>,
+    rustic_snapshot_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_snapshot_backup_start_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_snapshot_backup_end_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_snpashot_backup_duration_seconds: Series<Gauge<f64, AtomicU64>>,
+    rustic_snapshot_files_total: Series<Gauge>,
+    rustic_snapshot_size_bytes: Series<Gauge>,
+    rustic_snapshot_throughput_bytes_per_second: Series<Gauge<f64, AtomicU64>>,
+    rustic_latest_snapshot_timestamp: Series<Gauge<f64, AtomicU64>>,
+    rustic_latest_snapshot_backup_duration_seconds: Series<Gauge<f64, AtomicU64>>,
+    rustic_latest_snapshot_files_total: Series<Gauge>,
+    rustic_latest_snapshot_size_bytes: Series<Gauge>,
+}
+
+// Newest snapshot of each group, grouped by hostname, label and paths like rustic does
+fn latest_per_group<'a>(snapshots: &[&'a SnapshotFile]) -> Vec<&'a SnapshotFile> {
+    let mut latest: HashMap<(&str, &str, String), &SnapshotFile> = HashMap::new();
+    for &snapshot in snapshots {
+        let key = (
+            snapshot.hostname.as_str(),
+            snapshot.label.as_str(),
+            snapshot.paths.to_string(),
+        );
+        let entry = latest.entry(key).or_insert(snapshot);
+        if snapshot.time > entry.time {
+            *entry = snapshot;
+        }
+    }
+    latest.into_values().collect()
+}
+
+// Length of short snapshot ids, grown past SHORT_ID_LENGTH until the ids of
+// all snapshots of the repository are unique
+fn short_id_length(snapshots: &[SnapshotFile]) -> usize {
+    let ids: Vec<String> = snapshots.iter().map(|s| s.id.to_string()).collect();
+    let full = ids.iter().map(String::len).max().unwrap_or(SHORT_ID_LENGTH);
+    (SHORT_ID_LENGTH..full)
+        .find(|&length| {
+            let prefixes: HashSet<&str> =
+                ids.iter().map(|id| &id[..length.min(id.len())]).collect();
+            prefixes.len() == ids.len()
+        })
+        .unwrap_or(full)
+}
+
+// Snapshot id of a label, truncated to the short id length if enabled
+fn format_id(id: &impl ToString, length: Option<usize>) -> String {
+    let mut id = id.to_string();
+    if let Some(length) = length {
+        id.truncate(length);
+    }
+    id
+}
+
+// Delay before opening the repository again after consecutive failures,
+// doubling up to the collection interval. The jitter keeps repositories of
+// the same backend from retrying in lockstep.
+fn open_retry_delay(failures: u32, interval: Duration) -> Duration {
+    let max = interval.max(OPEN_RETRY_DELAY);
+    let delay = OPEN_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(max);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+// Backup duration of a snapshot in seconds, in whole seconds for corrupt
+// summaries too long for microseconds
+fn backup_duration_seconds(summary: &SnapshotSummary) -> f64 {
+    let duration = summary.backup_end - summary.backup_start;
+    duration
+        .num_microseconds()
+        .map_or(duration.num_seconds() as f64, |micros| {
+            micros as f64 / (10f64.powf(6.0))
+        })
 }
 
 impl RusticCollector {
-    pub fn new(backup: Backup, interval: u64) -> Self {
+    /// Collector opening and collecting the repository in the background,
+    /// within the limits shared with the other collectors
+    pub fn new(backup: Backup, interval: u64, limits: Limits) -> Self {
+        let collector = Self {
+            interval: backup.interval.unwrap_or(interval),
+            backup,
+            selection: Arc::default(),
+            state: Arc::new(Mutex::new(State::default())),
+            cycle: Arc::default(),
+            refresh: Arc::default(),
+            limits,
+        };
+        let task = Self::start(collector.clone());
+        collector.state.lock().unwrap().task = Some(task.abort_handle());
+        collector
+    }
+
+    /// Open the repository and collect its metrics once, without background
+    /// collection, for a repository probed on demand
+    pub async fn collect_once(backup: Backup, limits: Limits) -> Self {
         let collector = Self {
+            interval: 0,
             backup,
-            interval,
+            selection: Arc::default(),
             state: Arc::new(Mutex::new(State::default())),
+            cycle: Arc::default(),
+            refresh: Arc::default(),
+            limits,
         };
-        Self::start(collector.clone());
+        match Self::set_repository(collector.clone()).await {
+            Ok(()) => Self::update_data(collector.clone()).await,
+            Err(e) => {
+                error!(
+                    "Cannot open the repository: {}, error: {}",
+                    collector.backup.name, e
+                );
+                collector.record_error(&e);
+            }
+        }
         collector
     }
 
-    fn start(self) {
+    /// Handle to the same collection, exposing only the selected metrics
+    pub fn with_selection(&self, selection: Arc<MetricSelection>) -> Self {
+        Self {
+            selection,
+            ..self.clone()
+        }
+    }
+
+    /// Whether the repository has been opened
+    /// Whether both are the same collector
+    #[cfg(test)]
+    pub fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state.lock().unwrap().ready
+    }
+
+    pub fn status(&self) -> CollectorStatus {
+        let state = self.state.lock().unwrap();
+        CollectorStatus {
+            ready: state.ready,
+            running: state.task.as_ref().is_some_and(|task| !task.is_finished()),
+            collecting: state.collecting,
+            cycle_start: state.cycle_start,
+            cycle_end: state.cycle_end,
+            next_cycle: state.next_cycle,
+            last_error: state.last_error.clone(),
+            last_success: state.last_success,
+            failing: state.cycle_end.is_some() && !state.collection_success,
+            snapshots: state.collected.snapshots.len(),
+        }
+    }
+
+    /// Number of finished collection cycles and the end of the last one,
+    /// `None` if the metrics also depend on the time of the scrape
+    pub fn generation(&self) -> Option<(u64, Option<DateTime<Local>>)> {
+        if self.backup.schedule.is_some()
+            || self.backup.filter.max_age.is_some()
+            || self.backup.collection_mode != CollectionMode::Interval
+        {
+            return None;
+        }
+        let state = self.state.lock().unwrap();
+        Some((state.generation, state.cycle_end))
+    }
+
+    /// Snapshots of the last collection, as selected by the snapshot filter
+    pub fn snapshots(&self) -> Vec<SnapshotFile> {
+        let collected = self.state.lock().unwrap().collected.clone();
+        collected
+            .snapshots
+            .iter()
+            .filter(|s| self.backup.filter.matches(s))
+            .cloned()
+            .collect()
+    }
+
+    /// Start a collection cycle without waiting for the interval, or right
+    /// after the running one. Returns false if the background task has
+    /// ended.
+    pub fn refresh(&self) -> bool {
+        if !self.status().running {
+            return false;
+        }
+        self.refresh.notify_one();
+        true
+    }
+
+    /// Stop the background collection of the repository
+    pub fn stop(&self) {
+        if let Some(task) = self.state.lock().unwrap().task.take() {
+            task.abort();
+        }
+    }
+
+    /// Wait for the running collection cycle, then stop the background
+    /// collection and close the repository
+    pub async fn shutdown(&self) {
+        let _cycle = self.cycle.lock().await;
+        self.stop();
+        let mut state = self.state.lock().unwrap();
+        state.repository = None;
+        state.ready = false;
+    }
+
+    fn start(self) -> JoinHandle<()> {
         tokio::spawn(async move {
-            Self::set_repository(self.clone()).await;
+            let interval = Duration::from_secs(self.interval);
+            let on_scrape = self.backup.collection_mode != CollectionMode::Interval;
+            let mut refreshed = false;
+            let mut spread = self.backup.spread;
             loop {
-                Self::update_data(self.clone()).await;
-                tokio::time::sleep(Duration::from_secs(self.interval)).await;
+                let next = {
+                    let _cycle = self.cycle.lock().await;
+                    self.cycle_started();
+                    let next = match self.open().await {
+                        // collections on scrape only open the repository in
+                        // the background, unless refreshed
+                        Ok(()) => {
+                            if !on_scrape || refreshed {
+                                Self::update_data(self.clone()).await;
+                            }
+                            (!on_scrape).then(|| self.next_delay(interval, &mut spread))
+                        }
+                        Err(retry) => Some(retry),
+                    };
+                    self.close_unless_kept();
+                    self.cycle_finished(next);
+                    next
+                };
+                let wait = async {
+                    match next {
+                        Some(next) => tokio::time::sleep(next).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                refreshed = tokio::select! {
+                    _ = wait => false,
+                    _ = self.refresh.notified() => {
+                        info!("Refreshing metrics, repository: {}", self.backup.name);
+                        true
+                    }
+                };
             }
-        });
+        })
     }
 
-    async fn set_repository(self) {
-        let opts = RepositoryOptions::default().password(self.backup.password);
-        let backend = BackendOptions::default()
-            .repository(self.backup.repository)
-            .options(self.backup.options)
-            .to_backends()
-            .unwrap();
-        let repository_result = tokio::task::spawn_blocking(move || {
-            Repository::new(&opts, &backend).unwrap().open().unwrap()
+    // Delay until the next collection. When spreading, the first one is at a
+    // random point of the interval, so the cycles of the repositories do not
+    // line up. The others are shifted by up to the jitter either way.
+    fn next_delay(&self, interval: Duration, spread: &mut bool) -> Duration {
+        let mut rng = rand::thread_rng();
+        if std::mem::take(spread) {
+            return interval.mul_f64(rng.gen_range(0.0..1.0));
+        }
+        match self.backup.jitter {
+            Some(jitter) if !jitter.is_zero() => {
+                let shift = jitter.as_secs_f64() * rng.gen_range(-1.0..=1.0);
+                Duration::from_secs_f64((interval.as_secs_f64() + shift).max(0.0))
+            }
+            _ => interval,
+        }
+    }
+
+    /// Collect the repository on scrape as the collection mode says: right
+    /// away, or once the cached data is stale, in the background if set
+    pub async fn scraped(&self) {
+        if self.backup.collection_mode == CollectionMode::Cached {
+            let max_staleness = self
+                .backup
+                .max_staleness
+                .unwrap_or(Duration::from_secs(self.interval));
+            let fresh = self
+                .state
+                .lock()
+                .unwrap()
+                .last_collection
+                .and_then(|last| (Local::now() - last).to_std().ok())
+                .is_some_and(|age| age < max_staleness);
+            if fresh {
+                return;
+            }
+            if self.backup.background_refresh {
+                let collector = self.clone();
+                tokio::spawn(async move { collector.collect().await });
+                return;
+            }
+        }
+        self.collect().await;
+    }
+
+    /// Collect the repository now and wait for it, or for the cycle already
+    /// running, for collections on scrape. The background task opens the
+    /// repository, nothing is collected until it has been opened once.
+    pub async fn collect(&self) {
+        let collector = self.clone();
+        // the cycle goes on if the scrape stops waiting for it
+        let _ = tokio::spawn(async move {
+            let Ok(_cycle) = collector.cycle.try_lock() else {
+                let _cycle = collector.cycle.lock().await;
+                return;
+            };
+            if !collector.is_ready() {
+                return;
+            }
+            collector.cycle_started();
+            // reopened here if not kept open
+            let retry = match collector.open().await {
+                Ok(()) => {
+                    Self::update_data(collector.clone()).await;
+                    None
+                }
+                Err(retry) => Some(retry),
+            };
+            collector.close_unless_kept();
+            collector.cycle_finished(retry);
         })
         .await;
+    }
 
-        let repository = match repository_result {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Cannot open the repository: {}", self.backup.name);
-                panic!("Error: {}", e);
-            }
+    // Open the repository unless it is open. On failure, returns the delay
+    // before the next attempt, backing off from the other backups
+    async fn open(&self) -> Result<(), Duration> {
+        if self.state.lock().unwrap().repository.is_some() {
+            return Ok(());
+        }
+        let Err(e) = Self::set_repository(self.clone()).await else {
+            return Ok(());
         };
+        self.record_error(&e);
+        let failures = {
+            let mut state = self.state.lock().unwrap();
+            state.collection_success = false;
+            state.open_failures += 1;
+            state.open_failures
+        };
+        let delay = open_retry_delay(failures, Duration::from_secs(self.interval));
+        error!(
+            "Cannot open the repository: {}, error: {}, retrying in {}",
+            self.backup.name,
+            e,
+            humantime::format_duration(Duration::from_secs(delay.as_secs()))
+        );
+        Err(delay)
+    }
+
+    // Drop the repository handle at the end of a cycle unless the backup
+    // keeps it open, the data of the cycle stays exported
+    fn close_unless_kept(&self) {
+        if !self.backup.keep_open {
+            self.state.lock().unwrap().repository = None;
+        }
+    }
+
+    fn record_error(&self, error: &CollectionError) {
+        let mut state = self.state.lock().unwrap();
+        *state.collection_errors.entry(error.kind).or_default() += 1;
+        state.last_error = Some(error.to_string());
+    }
+
+    fn cycle_started(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.collecting = true;
+        state.cycle_start = Some(Local::now());
+        state.next_cycle = None;
+    }
+
+    // End of a cycle, the next one starts after the delay if any
+    fn cycle_finished(&self, next: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Local::now();
+        state.collecting = false;
+        state.cycle_end = Some(now);
+        state.generation += 1;
+        state.next_cycle =
+            next.and_then(|next| chrono::Duration::from_std(next).ok().map(|next| now + next));
+    }
+
+    // Run a blocking repository operation, bounded by the backup timeout.
+    // A timed out operation keeps running in the background, its result is
+    // dropped. Until it returns, the next operations fail right away instead
+    // of taking up more blocking threads.
+    async fn run_blocking<T, F>(&self, operation: F) -> Result<T, CollectionError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, CollectionError> + Send + 'static,
+    {
+        let hung = self.state.lock().unwrap().hung.take();
+        if let Some(hung) = hung.filter(|task| !task.is_finished()) {
+            self.state.lock().unwrap().hung = Some(hung);
+            return Err(CollectionError::from_error(
+                "A timed out operation is still running",
+            ));
+        }
+        // the permit is released on timeout, so hanging backends do not stall
+        // the collection of the other repositories. A repository has at most
+        // one hung operation, they are tracked by `hung`.
+        let _permit = self
+            .limits
+            .collections
+            .acquire()
+            .await
+            .map_err(CollectionError::from_error)?;
+        let task = tokio::task::spawn_blocking(operation);
+        let handle = task.abort_handle();
+        let result = match self.backup.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, task).await.map_err(|_| {
+                self.state.lock().unwrap().hung = Some(handle);
+                CollectionError::from_error(format!(
+                    "Operation timed out after {}",
+                    humantime::format_duration(timeout)
+                ))
+            })?,
+            None => task.await,
+        };
+        result.map_err(CollectionError::from_error)?
+    }
+
+    async fn set_repository(self) -> Result<(), CollectionError> {
+        let _permit = self
+            .limits
+            .opens
+            .acquire()
+            .await
+            .map_err(CollectionError::from_error)?;
+        self.state.lock().unwrap().open_attempts += 1;
+        // secrets are fetched on every open. An open handle keeps the old
+        // ones until the backend rejects them: the auth error drops the
+        // handle and the next open fetches the rotated secrets.
+        let mut backup = self.backup.clone();
+        backup
+            .apply_vault()
+            .await
+            .map_err(CollectionError::from_error)?;
+        backup
+            .apply_aws_secrets()
+            .await
+            .map_err(CollectionError::from_error)?;
+        let opts = backup
+            .repository_options()
+            .map_err(CollectionError::from_error)?;
+        let backend = backup
+            .backend_options()
+            .map_err(CollectionError::from_error)?
+            .to_backends()
+            .map_err(CollectionError::from_error)?;
+        let repository = self
+            .run_blocking(move || {
+                Repository::new(&opts, &backend)
+                    .and_then(|r| r.open())
+                    .map_err(CollectionError::from_error)
+            })
+            .await?;
 
         let mut state = self.state.lock().unwrap();
+        state.repository_config = Some(repository.config().clone());
         state.repository = Some(repository);
         state.ready = true;
+        state.open_failures = 0;
         info!("Repository is ready, repository: {}", self.backup.name);
+        Ok(())
     }
 
     async fn update_data(self) {
         debug!("Updating metrics, repository: {}", self.backup.name);
-        tokio::task::spawn_blocking(move || {
-            let mut state = self.state.lock().unwrap();
-            let repository = state.repository.as_ref().unwrap();
-            let snapshots = repository
-                .update_all_snapshots(state.snapshots.clone())
-                .unwrap();
-            state.snapshots = snapshots
-        })
-        .await
-        .unwrap();
-        debug!(
-            "Successfully updated metrics, repository: {}",
-            self.backup.name
-        );
+        let start = Instant::now();
+        let keep = self.backup.keep.clone();
+        let filter = self.backup.filter.clone();
+        let pack_size_buckets = self.backup.pack_size_buckets.clone();
+        // work on a copy, so a hanging backend does not hold the state lock
+        let (repository, cached) = {
+            let state = self.state.lock().unwrap();
+            let Some(repository) = state.repository.clone() else {
+                warn!("Repository is not open, repository: {}", self.backup.name);
+                return;
+            };
+            (repository, state.collected.snapshots.clone())
+        };
+        let result = self
+            .run_blocking(move || {
+                let snapshots = repository
+                    .update_all_snapshots(cached)
+                    .map_err(CollectionError::from_error)?;
+
+                // dry-run forget to see what the keep policy would remove
+                let forget = match keep {
+                    Some(keep) => {
+                        let group_by = SnapshotGroupCriterion::default();
+                        let groups = repository
+                            .get_forget_snapshots(&keep, group_by, |s| filter.matches(s))
+                            .map_err(CollectionError::from_error)?;
+                        let mut summary = ForgetSummary::default();
+                        for snapshot in groups.0.iter().flat_map(|g| &g.snapshots) {
+                            if !snapshot.keep {
+                                summary.to_forget += 1;
+                            }
+                            for reason in &snapshot.reasons {
+                                *summary.kept_by_reason.entry(reason.clone()).or_default() += 1;
+                            }
+                        }
+                        Some(summary)
+                    }
+                    None => None,
+                };
+
+                let pack_sizes = pack_size_buckets
+                    .map(|buckets| index::pack_size_histogram(&repository, &buckets))
+                    .transpose()?;
+                Ok((snapshots, forget, pack_sizes))
+            })
+            .await
+            .map(|(snapshots, forget, pack_sizes)| {
+                let mut state = self.state.lock().unwrap();
+                // snapshots gone since the last cycle were forgotten or pruned
+                let current: HashSet<_> = snapshots.iter().map(|s| s.id).collect();
+                let removed: Vec<String> = state
+                    .collected
+                    .snapshots
+                    .iter()
+                    .filter(|s| !current.contains(&s.id))
+                    .map(|s| s.id.to_string())
+                    .collect();
+                if !removed.is_empty() {
+                    info!(
+                        "Snapshots removed, repository: {}, snapshot_ids: {}",
+                        self.backup.name,
+                        removed.join(", ")
+                    );
+                    state.snapshots_removed += removed.len() as u64;
+                }
+                state.collected = Arc::new(Collected {
+                    snapshots,
+                    forget,
+                    pack_sizes,
+                });
+            });
+
+        let success = match result {
+            Ok(()) => {
+                debug!(
+                    "Successfully updated metrics, repository: {}",
+                    self.backup.name
+                );
+                true
+            }
+            Err(e) => {
+                // the snapshots of the last successful cycle stay exported,
+                // the next cycle tries again
+                error!(
+                    "Failed to update metrics, keeping the previous snapshots, repository: {}, error: {}",
+                    self.backup.name, e
+                );
+                self.record_error(&e);
+                // the next cycle opens a new handle
+                if e.is_connection_lost() {
+                    info!(
+                        "Dropping the repository handle to reopen it, repository: {}",
+                        self.backup.name
+                    );
+                    self.state.lock().unwrap().repository = None;
+                }
+                false
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        state.last_collection = Some(Local::now());
+        if success {
+            state.last_success = state.last_collection;
+        }
+        state.collection_success = success;
+        state.collection_duration = start.elapsed().as_secs_f64();
+    }
+}
+
+impl RusticCollector {
+    // Labels of a per snapshot series, with the templated labels of the backup
+    fn snapshot_labels(&self, labels: impl Into<Labels>, snapshot: &SnapshotFile) -> Labels {
+        let mut labels = labels.into();
+        for (name, template) in &self.backup.label_templates {
+            let value = template.render(snapshot);
+            // like in Prometheus, an empty value is the same as no label
+            if !value.is_empty() {
+                labels.push((name.clone(), value));
+            }
+        }
+        labels
+    }
+
+    fn encode_family(
+        &self,
+        encoder: &mut DescriptorEncoder,
+        name: &str,
+        help: &str,
+        metric: &impl EncodeMetric,
+    ) -> Result<(), std::fmt::Error> {
+        encode_family(&self.selection, encoder, name, help, metric)
+    }
+}
+
+// Encode a metric family unless it is disabled by the configuration
+fn encode_family(
+    selection: &MetricSelection,
+    encoder: &mut DescriptorEncoder,
+    name: &str,
+    help: &str,
+    metric: &impl EncodeMetric,
+) -> Result<(), std::fmt::Error> {
+    debug_assert!(FAMILIES.contains(&name), "unknown family {}", name);
+    if !selection.is_enabled(name) {
+        return Ok(());
     }
+    metric.encode(encoder.encode_descriptor(name, help, None, metric.metric_type())?)
+}
+
+/// Names of the metric families encoded by the collectors, without prefix
+pub const FAMILIES: &[&str] = &[
+    "backup_duration_seconds_histogram",
+    "backup_enabled",
+    "backup_on_schedule",
+    "backup_overdue_seconds",
+    "collection_duration_seconds",
+    "collection_errors",
+    "collection_last_success_timestamp",
+    "collection_staleness_seconds",
+    "collection_success",
+    "latest_snapshot_backup_duration_seconds",
+    "latest_snapshot_files_total",
+    "latest_snapshot_size_bytes",
+    "latest_snapshot_timestamp",
+    "oldest_snapshot_timestamp",
+    "pack_size_bytes",
+    "repository_chunker_polynomial_present",
+    "repository_compression_enabled",
+    "repository_compression_level",
+    "repository_datapack_size_bytes",
+    "repository_hosts",
+    "repository_info",
+    "repository_open_attempts",
+    "repository_open_failures",
+    "repository_ready",
+    "repository_treepack_size_bytes",
+    "repository_version",
+    "series_dropped",
+    "snapshot_backup_end_timestamp",
+    "snapshot_backup_start_timestamp",
+    "snapshot_command_info",
+    "snapshot_files_total",
+    "snapshot_info",
+    "snapshot_original_info",
+    "snapshot_path_info",
+    "snapshot_size_bytes",
+    "snapshot_tag_info",
+    "snapshot_throughput_bytes_per_second",
+    "snapshot_timestamp",
+    "snapshots_by_host",
+    "snapshots_by_path",
+    "snapshots_kept_by_policy",
+    "snapshots_removed",
+    "snapshots_to_forget",
+    "snapshots_without_summary",
+    "snpashot_backup_duration_seconds",
+];
+
+/// Families of counters, exposed with a `_total` suffix
+pub const COUNTERS: &[&str] = &[
+    "collection_errors",
+    "repository_open_attempts",
+    "series_dropped",
+    "snapshots_removed",
+];
+
+const BACKUP_ENABLED_HELP: &str = "Whether the backup is enabled in the configuration.";
+
+/// Stands in for a backup with `enabled = false`, only exposing that it is
+/// disabled
+#[derive(Debug)]
+pub struct DisabledCollector {
+    name: String,
+    options: Arc<SeriesOptions>,
+    selection: Arc<MetricSelection>,
 }
 
+impl DisabledCollector {
+    pub fn new(backup: &Backup, selection: Arc<MetricSelection>) -> Self {
+        Self {
+            name: backup.name.clone(),
+            options: Arc::new(SeriesOptions::new(backup.relabel.clone(), None)),
+            selection,
+        }
+    }
+}
+
+impl Collector for DisabledCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let backup_enabled = Series::<Gauge>::new(&self.options);
+        backup_enabled
+            .get_or_create(&RepositoryLabels {
+                repo_name: self.name.clone(),
+            })
+            .set(0);
+        encode_family(
+            &self.selection,
+            &mut encoder,
+            "backup_enabled",
+            BACKUP_ENABLED_HELP,
+            &backup_enabled,
+        )
+    }
+}
+
+// Metric names are encoded without prefix, the registry adds the configured
+// prefix which defaults to `rustic`
 impl Collector for RusticCollector {
     fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
-        let data = self.state.lock().unwrap();
+        let data = self.state.lock().unwrap().exported();
+
+        let options = Arc::new(SeriesOptions::new(
+            self.backup.relabel.clone(),
+            self.backup.max_series,
+        ));
+        let metrics = Metrics {
+            rustic_backup_enabled: Series::new(&options),
+            rustic_collection_success: Series::new(&options),
+            rustic_repository_ready: Series::new(&options),
+            rustic_repository_open_attempts: Series::new(&options),
+            rustic_repository_open_failures: Series::new(&options),
+            rustic_collection_duration_seconds: Series::new(&options),
+            rustic_collection_last_success_timestamp: Series::new(&options),
+            rustic_collection_staleness_seconds: Series::new(&options),
+            rustic_collection_errors: Series::new(&options),
+            rustic_repository_info: Series::new(&options),
+            rustic_repository_version: Series::new(&options),
+            rustic_repository_chunker_polynomial_present: Series::new(&options),
+            rustic_repository_compression_enabled: Series::new(&options),
+            rustic_repository_compression_level: Series::new(&options),
+            rustic_repository_treepack_size_bytes: Series::new(&options),
+            rustic_repository_datapack_size_bytes: Series::new(&options),
+            rustic_repository_hosts: Series::new(&options),
+            rustic_oldest_snapshot_timestamp: Series::new(&options),
+            rustic_backup_on_schedule: Series::new(&options),
+            rustic_backup_overdue_seconds: Series::new(&options),
+            rustic_snapshots_to_forget: Series::new(&options),
+            rustic_snapshots_removed: Series::new(&options),
+            rustic_snapshots_without_summary: Series::new(&options),
+            rustic_snapshots_kept_by_policy: Series::new(&options),
+            rustic_snapshot_info: Series::new(&options),
+            rustic_snapshot_path_info: Series::new(&options),
+            rustic_snapshot_tag_info: Series::new(&options),
+            rustic_snapshot_command_info: Series::new(&options),
+            rustic_snapshot_original_info: Series::new(&options),
+            rustic_snapshots_by_host: Series::new(&options),
+            rustic_snapshots_by_path: Series::new(&options),
+            rustic_snapshot_timestamp: Series::new(&options),
+            rustic_snapshot_backup_end_timestamp: Series::new(&options),
+            rustic_snapshot_backup_start_timestamp: Series::new(&options),
+            rustic_snpashot_backup_duration_seconds: Series::new(&options),
+            rustic_snapshot_files_total: Series::new(&options),
+            rustic_snapshot_size_bytes: Series::new(&options),
+            rustic_snapshot_throughput_bytes_per_second: Series::new(&options),
+            rustic_latest_snapshot_timestamp: Series::new(&options),
+            rustic_latest_snapshot_backup_duration_seconds: Series::new(&options),
+            rustic_latest_snapshot_files_total: Series::new(&options),
+            rustic_latest_snapshot_size_bytes: Series::new(&options),
+        };
+
+        //-- Collection metrics
+        // always exposed, so a repository that never becomes ready is still visible
+        let repository_labels = RepositoryLabels {
+            repo_name: self.backup.name.clone(),
+        };
+        metrics
+            .rustic_backup_enabled
+            .get_or_create(&repository_labels)
+            .set(1);
+        metrics
+            .rustic_collection_success
+            .get_or_create(&repository_labels)
+            .set(data.collection_success as i64);
+        metrics
+            .rustic_repository_ready
+            .get_or_create(&repository_labels)
+            .set(data.ready as i64);
+        metrics
+            .rustic_repository_open_attempts
+            .get_or_create(&repository_labels)
+            .inc_by(data.open_attempts);
+        metrics
+            .rustic_repository_open_failures
+            .get_or_create(&repository_labels)
+            .set(data.open_failures as i64);
+        metrics
+            .rustic_collection_duration_seconds
+            .get_or_create(&repository_labels)
+            .set(data.collection_duration);
+        if let Some(last_success) = data.last_success {
+            metrics
+                .rustic_collection_last_success_timestamp
+                .get_or_create(&repository_labels)
+                .set(last_success.timestamp_micros() as f64 / (10f64.powf(6.0)));
+            // data collected on scrape may be served stale
+            if self.backup.collection_mode != CollectionMode::Interval {
+                let staleness = Local::now() - last_success;
+                metrics
+                    .rustic_collection_staleness_seconds
+                    .get_or_create(&repository_labels)
+                    .set(staleness.num_milliseconds() as f64 / 1000.0);
+            }
+        }
+        for (kind, count) in &data.collection_errors {
+            metrics
+                .rustic_collection_errors
+                .get_or_create(&CollectionErrorLabels {
+                    repo_name: self.backup.name.clone(),
+                    kind: kind.as_str().to_string(),
+                })
+                .inc_by(*count);
+        }
+        self.encode_family(
+            &mut encoder,
+            "backup_enabled",
+            BACKUP_ENABLED_HELP,
+            &metrics.rustic_backup_enabled,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "collection_success",
+            "Whether the last collection cycle of a repository succeeded.",
+            &metrics.rustic_collection_success,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_ready",
+            "Whether the repository of a backup is open.",
+            &metrics.rustic_repository_ready,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_open_attempts",
+            "Attempts to open the repository of a backup.",
+            &metrics.rustic_repository_open_attempts,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_open_failures",
+            "Consecutive failed attempts to open the repository of a backup, 0 once open.",
+            &metrics.rustic_repository_open_failures,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "collection_duration_seconds",
+            "Duration of the last collection cycle of a repository.",
+            &metrics.rustic_collection_duration_seconds,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "collection_last_success_timestamp",
+            "Time of the last successful collection cycle of a repository.",
+            &metrics.rustic_collection_last_success_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "collection_staleness_seconds",
+            "Age of the data of a repository collected on scrape.",
+            &metrics.rustic_collection_staleness_seconds,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "collection_errors",
+            "Collection errors of a repository by error class.",
+            &metrics.rustic_collection_errors,
+        )?;
 
         //-- Set metrics
         // return if repository is not ready
@@ -144,17 +1208,8 @@ impl Collector for RusticCollector {
             return Ok(());
         }
 
-        let repo = data.repository.as_ref().unwrap();
-        let repo_config = repo.config();
-        let metrics = Metrics {
-            rustic_repository_info: Family::default(),
-            rustic_snapshot_info: Family::default(),
-            rustic_snapshot_timestamp: Family::default(),
-            rustic_snapshot_backup_end_timestamp: Family::default(),
-            rustic_snapshot_backup_start_timestamp: Family::default(),
-            rustic_snpashot_backup_duration_seconds: Family::default(),
-            rustic_snapshot_files_total: Family::default(),
-            rustic_snapshot_size_bytes: Family::default(),
+        let Some(repo_config) = data.repository_config.as_ref() else {
+            return Ok(());
         };
 
         // set repository metrics
@@ -167,46 +1222,267 @@ impl Collector for RusticCollector {
             })
             .set(1);
 
-        // set snapshot metrics
-        for snapshot in &data.snapshots {
-            let snapshot_info_labels = SnapshotInfoLabels {
-                repo_name: self.backup.name.clone(),
-                repo_id: repo_config.id.to_string(),
-                snapshot_id: snapshot.id.to_string(),
-                paths: snapshot.paths.to_string(),
-                tags: snapshot.tags.to_string(),
-                hostname: snapshot.hostname.to_string(),
-                username: snapshot.username.to_string(),
-                program_version: snapshot.program_version.to_string(),
-            };
+        let repository_id_labels = RepositoryIdLabels {
+            repo_name: self.backup.name.clone(),
+            repo_id: repo_config.id.to_string(),
+        };
+        // set repository config metrics
+        metrics
+            .rustic_repository_version
+            .get_or_create(&repository_id_labels)
+            .set(repo_config.version as i64);
+        metrics
+            .rustic_repository_chunker_polynomial_present
+            .get_or_create(&repository_id_labels)
+            .set(!repo_config.chunker_polynomial.is_empty() as i64);
+        // compression needs repository version 2, level 0 is the zstd default level
+        let compression_enabled = repo_config.version >= 2 && repo_config.compression != Some(0);
+        metrics
+            .rustic_repository_compression_enabled
+            .get_or_create(&repository_id_labels)
+            .set(compression_enabled as i64);
+        if compression_enabled {
+            metrics
+                .rustic_repository_compression_level
+                .get_or_create(&repository_id_labels)
+                .set(repo_config.compression.unwrap_or(0) as i64);
+        }
+        // pack sizes are only exposed if set, otherwise rustic defaults apply
+        if let Some(size) = repo_config.treepack_size {
+            metrics
+                .rustic_repository_treepack_size_bytes
+                .get_or_create(&repository_id_labels)
+                .set(size as i64);
+        }
+        if let Some(size) = repo_config.datapack_size {
+            metrics
+                .rustic_repository_datapack_size_bytes
+                .get_or_create(&repository_id_labels)
+                .set(size as i64);
+        }
 
-            let snapshot_labels = SnapshotLabels {
-                repo_name: self.backup.name.clone(),
-                repo_id: repo_config.id.to_string(),
-                snapshot_id: snapshot.id.to_string(),
-            };
+        // only filtered snapshots are turned into metrics
+        let snapshots: Vec<&SnapshotFile> = data
+            .collected
+            .snapshots
+            .iter()
+            .filter(|s| self.backup.filter.matches(s))
+            .collect();
+
+        let hosts: HashSet<&str> = snapshots.iter().map(|s| s.hostname.as_str()).collect();
+        metrics
+            .rustic_repository_hosts
+            .get_or_create(&repository_id_labels)
+            .set(hosts.len() as i64);
+
+        if let Some(oldest) = snapshots.iter().map(|s| s.time).min() {
+            metrics
+                .rustic_oldest_snapshot_timestamp
+                .get_or_create(&repository_id_labels)
+                .set(oldest.timestamp_micros() as f64 / (10f64.powf(6.0)));
+        }
+
+        // set schedule compliance metrics
+        let latest = snapshots.iter().map(|s| s.time).max();
+        if let Some(compliance) = self
+            .backup
+            .schedule
+            .as_ref()
+            .and_then(|s| s.evaluate(latest, Local::now()))
+        {
+            metrics
+                .rustic_backup_on_schedule
+                .get_or_create(&repository_id_labels)
+                .set(compliance.on_schedule as i64);
+            if let Some(overdue) = compliance.overdue_seconds {
+                metrics
+                    .rustic_backup_overdue_seconds
+                    .get_or_create(&repository_id_labels)
+                    .set(overdue);
+            }
+        }
+
+        metrics
+            .rustic_snapshots_removed
+            .get_or_create(&repository_id_labels)
+            .inc_by(data.snapshots_removed);
+
+        // set retention policy metrics
+        if let Some(forget) = &data.collected.forget {
+            metrics
+                .rustic_snapshots_to_forget
+                .get_or_create(&repository_id_labels)
+                .set(forget.to_forget as i64);
+            for (reason, count) in &forget.kept_by_reason {
+                metrics
+                    .rustic_snapshots_kept_by_policy
+                    .get_or_create(&KeepReasonLabels {
+                        repo_name: self.backup.name.clone(),
+                        repo_id: repo_config.id.to_string(),
+                        reason: reason.clone(),
+                    })
+                    .set(*count as i64);
+            }
+        }
+
+        // set aggregated snapshot metrics
+        let snapshots_without_summary = metrics
+            .rustic_snapshots_without_summary
+            .get_or_create(&repository_id_labels)
+            .clone();
+        for &snapshot in &snapshots {
+            metrics
+                .rustic_snapshots_by_host
+                .get_or_create(&HostLabels {
+                    repo_name: self.backup.name.clone(),
+                    repo_id: repo_config.id.to_string(),
+                    hostname: snapshot.hostname.to_string(),
+                })
+                .inc();
+
+            metrics
+                .rustic_snapshots_by_path
+                .get_or_create(&PathLabels {
+                    repo_name: self.backup.name.clone(),
+                    repo_id: repo_config.id.to_string(),
+                    paths: snapshot.paths.to_string(),
+                })
+                .inc();
+
+            if snapshot.summary.is_none() {
+                snapshots_without_summary.inc();
+            }
+        }
+
+        // set per snapshot metrics
+        let mut exported = if self.backup.latest_only {
+            latest_per_group(&snapshots)
+        } else {
+            snapshots.clone()
+        };
+        let now = Local::now();
+        exported.retain(|s| self.backup.filter.within_max_age(s, now));
+        // newest first, so `max_series` drops the series of the oldest snapshots
+        exported.sort_by_key(|s| Reverse(s.time));
+        if let Some(max_snapshots) = self.backup.max_snapshots {
+            exported.truncate(max_snapshots);
+        }
+        let id_length = self
+            .backup
+            .short_ids
+            .then(|| short_id_length(&data.collected.snapshots));
+        for &snapshot in &exported {
+            let snapshot_id = format_id(&snapshot.id, id_length);
+            let mut snapshot_info_labels = vec![
+                ("repo_name".to_string(), self.backup.name.clone()),
+                ("repo_id".to_string(), repo_config.id.to_string()),
+                ("snapshot_id".to_string(), snapshot_id.clone()),
+            ];
+            for label in SnapshotInfoLabel::ALL {
+                if !self.backup.snapshot_info_label_enabled(label) {
+                    continue;
+                }
+                let value = match label {
+                    SnapshotInfoLabel::Paths => snapshot.paths.to_string(),
+                    SnapshotInfoLabel::Hostname => snapshot.hostname.to_string(),
+                    SnapshotInfoLabel::Username => snapshot.username.to_string(),
+                    SnapshotInfoLabel::Tags => snapshot.tags.to_string(),
+                    SnapshotInfoLabel::ProgramVersion => snapshot.program_version.to_string(),
+                };
+                snapshot_info_labels.push((label.as_str().to_string(), value));
+            }
+
+            let snapshot_info_labels = self.snapshot_labels(snapshot_info_labels, snapshot);
+            let snapshot_labels = self.snapshot_labels(
+                SnapshotLabels {
+                    repo_name: self.backup.name.clone(),
+                    repo_id: repo_config.id.to_string(),
+                    snapshot_id: snapshot_id.clone(),
+                },
+                snapshot,
+            );
 
             metrics
                 .rustic_snapshot_info
                 .get_or_create(&snapshot_info_labels)
                 .set(1);
 
+            if self.backup.split_paths {
+                for path in snapshot.paths.iter() {
+                    metrics
+                        .rustic_snapshot_path_info
+                        .get_or_create(&self.snapshot_labels(
+                            SnapshotPathLabels {
+                                repo_name: self.backup.name.clone(),
+                                repo_id: repo_config.id.to_string(),
+                                snapshot_id: snapshot_id.clone(),
+                                path: path.clone(),
+                            },
+                            snapshot,
+                        ))
+                        .set(1);
+                }
+            }
+            if self.backup.split_tags {
+                for tag in snapshot.tags.iter() {
+                    metrics
+                        .rustic_snapshot_tag_info
+                        .get_or_create(&self.snapshot_labels(
+                            SnapshotTagLabels {
+                                repo_name: self.backup.name.clone(),
+                                repo_id: repo_config.id.to_string(),
+                                snapshot_id: snapshot_id.clone(),
+                                tag: tag.clone(),
+                            },
+                            snapshot,
+                        ))
+                        .set(1);
+                }
+            }
+
+            // only snapshots created by copy have an original
+            if let Some(original) = &snapshot.original {
+                metrics
+                    .rustic_snapshot_original_info
+                    .get_or_create(&self.snapshot_labels(
+                        SnapshotOriginalLabels {
+                            repo_name: self.backup.name.clone(),
+                            repo_id: repo_config.id.to_string(),
+                            snapshot_id: snapshot_id.clone(),
+                            original_id: format_id(original, id_length),
+                        },
+                        snapshot,
+                    ))
+                    .set(1);
+            }
+
             metrics
                 .rustic_snapshot_timestamp
                 .get_or_create(&snapshot_labels)
                 .set(snapshot.time.timestamp_micros() as f64 / (10f64.powf(6.0)));
 
             // skip current iteration if snapshot summary having no data
-            if snapshot.summary.is_none() {
+            let Some(summary) = snapshot.summary.as_ref() else {
                 warn!(
                     "Snapshot summary has no data, repository: {}, snapshot_id: {} ",
                     self.backup.name,
                     snapshot.id.to_string()
                 );
                 continue;
-            }
+            };
 
-            let summary = snapshot.summary.as_ref().unwrap();
+            metrics
+                .rustic_snapshot_command_info
+                .get_or_create(&self.snapshot_labels(
+                    SnapshotCommandLabels {
+                        repo_name: self.backup.name.clone(),
+                        repo_id: repo_config.id.to_string(),
+                        snapshot_id: snapshot_id.clone(),
+                        command: summary.command.to_string(),
+                    },
+                    snapshot,
+                ))
+                .set(1);
 
             metrics
                 .rustic_snapshot_files_total
@@ -228,85 +1504,313 @@ impl Collector for RusticCollector {
                 .get_or_create(&snapshot_labels)
                 .set(summary.backup_end.timestamp_micros() as f64 / (10f64.powf(6.0)));
 
+            let backup_duration = backup_duration_seconds(summary);
+
             metrics
                 .rustic_snpashot_backup_duration_seconds
                 .get_or_create(&snapshot_labels)
-                .set(
-                    (summary.backup_end - summary.backup_start)
-                        .num_microseconds()
-                        .unwrap() as f64
-                        / (10f64.powf(6.0)),
-                );
+                .set(backup_duration);
+
+            // skip throughput for instant backups to avoid dividing by zero
+            if backup_duration > 0.0 {
+                metrics
+                    .rustic_snapshot_throughput_bytes_per_second
+                    .get_or_create(&snapshot_labels)
+                    .set(summary.total_bytes_processed as f64 / backup_duration);
+            }
         }
 
+        // set latest snapshot metrics, without per snapshot cardinality
+        let mut latest = latest_per_group(&snapshots);
+        latest.sort_by_key(|s| Reverse(s.time));
+        for snapshot in latest {
+            let group_labels = self.snapshot_labels(
+                SnapshotGroupLabels {
+                    repo_name: self.backup.name.clone(),
+                    repo_id: repo_config.id.to_string(),
+                    hostname: snapshot.hostname.to_string(),
+                    snapshot_label: snapshot.label.to_string(),
+                    paths: snapshot.paths.to_string(),
+                },
+                snapshot,
+            );
+
+            metrics
+                .rustic_latest_snapshot_timestamp
+                .get_or_create(&group_labels)
+                .set(snapshot.time.timestamp_micros() as f64 / (10f64.powf(6.0)));
+
+            let Some(summary) = snapshot.summary.as_ref() else {
+                continue;
+            };
+
+            metrics
+                .rustic_latest_snapshot_files_total
+                .get_or_create(&group_labels)
+                .set(summary.total_files_processed as i64);
+
+            metrics
+                .rustic_latest_snapshot_size_bytes
+                .get_or_create(&group_labels)
+                .set(summary.total_bytes_processed as i64);
+
+            metrics
+                .rustic_latest_snapshot_backup_duration_seconds
+                .get_or_create(&group_labels)
+                .set(backup_duration_seconds(summary));
+        }
+
+        // set backup duration histogram, only if buckets are configured
+        let duration_histogram = self.backup.duration_buckets.as_ref().map(|buckets| {
+            // the buckets are checked to be increasing when the configuration loads
+            let histogram = Histogram::new(buckets.iter().copied());
+            for summary in snapshots.iter().filter_map(|s| s.summary.as_ref()) {
+                histogram.observe(backup_duration_seconds(summary));
+            }
+            histogram
+        });
+
+        // every scrape drops the series over the cap again, they are only
+        // counted on the first scrape of a generation
+        let dropped = {
+            let mut state = self.state.lock().unwrap();
+            if state.series_dropped_generation != Some(data.generation) {
+                state.series_dropped_generation = Some(data.generation);
+                state.series_dropped += options.dropped();
+            }
+            state.series_dropped
+        };
+        let series_dropped = Series::<Counter>::new(&options);
+        series_dropped
+            .get_or_create(&repository_labels)
+            .inc_by(dropped);
+
         //-- Encode
-        metrics
-            .rustic_repository_info
-            .encode(encoder.encode_descriptor(
-                "rustic_repository_info",
-                "Repository information.",
-                None,
-                metrics.rustic_repository_info.metric_type(),
-            )?)?;
-        metrics
-            .rustic_snapshot_info
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_info",
-                "Snapshot inforamation.",
-                None,
-                metrics.rustic_snapshot_info.metric_type(),
-            )?)?;
-        metrics
-            .rustic_snapshot_files_total
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_files_total",
-                "Total files in a snapshot.",
-                None,
-                metrics.rustic_snapshot_files_total.metric_type(),
-            )?)?;
-        metrics
-            .rustic_snapshot_timestamp
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_timestamp",
-                "Snapshot creation time in unix timestamp.",
-                None,
-                metrics.rustic_snapshot_timestamp.metric_type(),
-            )?)?;
-        metrics
-            .rustic_snapshot_size_bytes
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_size_bytes",
-                "Snapshot size in bytes.",
-                None,
-                metrics.rustic_snapshot_size_bytes.metric_type(),
-            )?)?;
+        self.encode_family(
+            &mut encoder,
+            "repository_info",
+            "Repository information.",
+            &metrics.rustic_repository_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_version",
+            "Repository format version.",
+            &metrics.rustic_repository_version,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_chunker_polynomial_present",
+            "Whether the repository config has a chunker polynomial.",
+            &metrics.rustic_repository_chunker_polynomial_present,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_compression_enabled",
+            "Whether the repository compresses data.",
+            &metrics.rustic_repository_compression_enabled,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_compression_level",
+            "Zstd compression level of the repository, 0 is the zstd default.",
+            &metrics.rustic_repository_compression_level,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_treepack_size_bytes",
+            "Configured target size of tree packs in bytes.",
+            &metrics.rustic_repository_treepack_size_bytes,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_datapack_size_bytes",
+            "Configured target size of data packs in bytes.",
+            &metrics.rustic_repository_datapack_size_bytes,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "repository_hosts",
+            "Number of distinct hostnames across snapshots.",
+            &metrics.rustic_repository_hosts,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "oldest_snapshot_timestamp",
+            "Creation time of the oldest snapshot in unix timestamp.",
+            &metrics.rustic_oldest_snapshot_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "backup_on_schedule",
+            "Whether the latest snapshot meets the configured schedule.",
+            &metrics.rustic_backup_on_schedule,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "backup_overdue_seconds",
+            "Time since the configured schedule expected a snapshot.",
+            &metrics.rustic_backup_overdue_seconds,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_to_forget",
+            "Snapshots the configured keep policy would remove.",
+            &metrics.rustic_snapshots_to_forget,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_removed",
+            "Snapshots which disappeared from the repository, forgotten or pruned.",
+            &metrics.rustic_snapshots_removed,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_kept_by_policy",
+            "Snapshots kept by the configured keep policy per reason.",
+            &metrics.rustic_snapshots_kept_by_policy,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_without_summary",
+            "Snapshots without summary data, which are missing from summary based metrics.",
+            &metrics.rustic_snapshots_without_summary,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_info",
+            "Snapshot inforamation.",
+            &metrics.rustic_snapshot_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_path_info",
+            "Path backed up by a snapshot.",
+            &metrics.rustic_snapshot_path_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_tag_info",
+            "Tag of a snapshot.",
+            &metrics.rustic_snapshot_tag_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_command_info",
+            "Command which created a snapshot.",
+            &metrics.rustic_snapshot_command_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_original_info",
+            "Original snapshot of a copied snapshot.",
+            &metrics.rustic_snapshot_original_info,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_by_host",
+            "Number of snapshots per hostname.",
+            &metrics.rustic_snapshots_by_host,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshots_by_path",
+            "Number of snapshots per backed up path set.",
+            &metrics.rustic_snapshots_by_path,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_files_total",
+            "Total files in a snapshot.",
+            &metrics.rustic_snapshot_files_total,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_timestamp",
+            "Snapshot creation time in unix timestamp.",
+            &metrics.rustic_snapshot_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_size_bytes",
+            "Snapshot size in bytes.",
+            &metrics.rustic_snapshot_size_bytes,
+        )?;
 
-        metrics
-            .rustic_snapshot_backup_start_timestamp
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_backup_start_timestamp",
-                "Backup start time of a snapshot in unix timestamp.",
-                None,
-                metrics.rustic_snapshot_backup_start_timestamp.metric_type(),
-            )?)?;
-        metrics
-            .rustic_snapshot_backup_end_timestamp
-            .encode(encoder.encode_descriptor(
-                "rustic_snapshot_backup_end_timestamp",
-                "Backup finished time of a snapshot in unix timestamp.",
-                None,
-                metrics.rustic_snapshot_backup_end_timestamp.metric_type(),
-            )?)?;
-        metrics.rustic_snpashot_backup_duration_seconds.encode(
-            encoder.encode_descriptor(
-                "rustic_snpashot_backup_duration_seconds",
-                "Backup duration of a snapshot.",
-                None,
-                metrics
-                    .rustic_snpashot_backup_duration_seconds
-                    .metric_type(),
-            )?,
+        self.encode_family(
+            &mut encoder,
+            "snapshot_backup_start_timestamp",
+            "Backup start time of a snapshot in unix timestamp.",
+            &metrics.rustic_snapshot_backup_start_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_backup_end_timestamp",
+            "Backup finished time of a snapshot in unix timestamp.",
+            &metrics.rustic_snapshot_backup_end_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snpashot_backup_duration_seconds",
+            "Backup duration of a snapshot.",
+            &metrics.rustic_snpashot_backup_duration_seconds,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "snapshot_throughput_bytes_per_second",
+            "Bytes processed per second during the backup of a snapshot.",
+            &metrics.rustic_snapshot_throughput_bytes_per_second,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "latest_snapshot_timestamp",
+            "Creation time of the latest snapshot of a group in unix timestamp.",
+            &metrics.rustic_latest_snapshot_timestamp,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "latest_snapshot_files_total",
+            "Total files in the latest snapshot of a group.",
+            &metrics.rustic_latest_snapshot_files_total,
         )?;
+        self.encode_family(
+            &mut encoder,
+            "latest_snapshot_size_bytes",
+            "Size of the latest snapshot of a group in bytes.",
+            &metrics.rustic_latest_snapshot_size_bytes,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "latest_snapshot_backup_duration_seconds",
+            "Backup duration of the latest snapshot of a group.",
+            &metrics.rustic_latest_snapshot_backup_duration_seconds,
+        )?;
+        self.encode_family(
+            &mut encoder,
+            "series_dropped",
+            "Series dropped because a family reached max_series.",
+            &series_dropped,
+        )?;
+        if let Some(histogram) = duration_histogram {
+            let family = Series::new(&options);
+            family.insert(&repository_id_labels, histogram);
+            self.encode_family(
+                &mut encoder,
+                "backup_duration_seconds_histogram",
+                "Backup durations of all snapshots.",
+                &family,
+            )?;
+        }
+        if let Some(histogram) = &data.collected.pack_sizes {
+            let family = Series::new(&options);
+            family.insert(&repository_id_labels, histogram.clone());
+            self.encode_family(
+                &mut encoder,
+                "pack_size_bytes",
+                "Sizes of the pack files in the index.",
+                &family,
+            )?;
+        }
 
         Ok(())
     }
@@ -1,17 +1,1124 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use clap::ValueEnum;
+use regex::Regex;
+use rustic_backend::BackendOptions;
+use rustic_core::{KeepOptions, RepositoryOptions};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, io};
+
+use crate::backend::{RestBackend, S3Backend, SftpBackend};
+use crate::collector::{COUNTERS, FAMILIES};
+use crate::filter::SnapshotFilter;
+use crate::relabel::{is_valid_label_name, RelabelRule};
+use crate::schedule::Schedule;
+use crate::server::WebConfig;
+use crate::template::LabelTemplate;
+use crate::vault::VaultSecret;
+use tracing::warn;
+
+const CONFIG_ENV: &str = "RUSTIC_EXPORTER_CONFIG";
+const BACKUP_ENV_PREFIX: &str = "RUSTIC_EXPORTER_BACKUP_";
+const METRIC_PREFIX_ENV: &str = "RUSTIC_EXPORTER_METRIC_PREFIX";
+const DEFAULT_SECRETS_DIR: &str = "/run/secrets";
+/// Labels set by the exporter, which the labels of a backup cannot replace
+const RESERVED_LABELS: &[&str] = &[
+    "repo_name",
+    "repo_id",
+    "snapshot_id",
+    "snapshot_label",
+    "original_id",
+    "hostname",
+    "username",
+    "paths",
+    "path",
+    "tags",
+    "tag",
+    "command",
+    "program_version",
+    "version",
+    "kind",
+    "reason",
+];
 
 // Config related struct
 #[derive(Deserialize)]
 pub(crate) struct Config {
-    #[serde(rename = "backup")]
+    #[serde(default, rename = "backup")]
     pub(crate) backups: Vec<Backup>,
+    /// Settings of the repositories probed on /probe by module name, the
+    /// repository is the target of the probe
+    #[serde(default, rename = "module")]
+    pub(crate) modules: BTreeMap<String, Backup>,
+    /// Prefix of the metric names, `rustic` if omitted
+    #[serde(default, deserialize_with = "optional_metric_prefix")]
+    pub(crate) metric_prefix: Option<String>,
+    #[serde(default)]
+    pub(crate) metrics: MetricSelection,
+    /// HTTP server settings, only read at startup
+    #[serde(default)]
+    pub(crate) web: WebConfig,
+    /// Configuration as read, after the environment variables, includes
+    /// and defaults are applied
+    #[serde(skip)]
+    pub(crate) effective: Value,
+}
+
+/// Metric families to expose, by name with or without prefix
+#[derive(Clone, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct MetricSelection {
+    /// Only expose these families if set
+    pub(crate) enabled: Option<HashSet<String>>,
+    #[serde(default)]
+    pub(crate) disabled: HashSet<String>,
+}
+
+impl MetricSelection {
+    /// Strip the metric prefix from the configured names, and the `_total`
+    /// suffix of counters, so they match the names encoded by the collectors
+    pub(crate) fn without_prefix(self, prefix: &str) -> Self {
+        let strip = |names: HashSet<String>| -> HashSet<String> {
+            names
+                .into_iter()
+                .map(|name| {
+                    let name = [format!("{}_", prefix), "rustic_".to_string()]
+                        .iter()
+                        .find_map(|p| name.strip_prefix(p.as_str()))
+                        .map(str::to_string)
+                        .unwrap_or(name);
+                    match name.strip_suffix("_total") {
+                        Some(counter) if COUNTERS.contains(&counter) => counter.to_string(),
+                        _ => name,
+                    }
+                })
+                .collect()
+        };
+        let selection = Self {
+            enabled: self.enabled.map(strip),
+            disabled: strip(self.disabled),
+        };
+        for name in selection
+            .enabled
+            .iter()
+            .flatten()
+            .chain(&selection.disabled)
+        {
+            if !FAMILIES.contains(&name.as_str()) {
+                warn!("Unknown metric family in [metrics]: {}", name);
+            }
+        }
+        selection
+    }
+
+    pub(crate) fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.as_ref().is_none_or(|e| e.contains(name)) && !self.disabled.contains(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    // detect the format by file extension, defaults to TOML
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(&self, content: &str) -> Result<T, String> {
+        match self {
+            ConfigFormat::Toml => {
+                toml::from_str(content).map_err(|e| format!("Invalid toml file: {}", e))
+            }
+            ConfigFormat::Yaml => {
+                serde_yaml::from_str(content).map_err(|e| format!("Invalid yaml file: {}", e))
+            }
+            ConfigFormat::Json => {
+                serde_json::from_str(content).map_err(|e| format!("Invalid json file: {}", e))
+            }
+        }
+    }
+}
+
+/// Where and how to load the configuration from
+#[derive(Clone, Debug)]
+pub(crate) struct ConfigSource {
+    /// Configuration file or directory, the environment is used if omitted
+    pub(crate) path: Option<String>,
+    pub(crate) format: Option<ConfigFormat>,
+    pub(crate) strict_env: bool,
+}
+
+impl ConfigSource {
+    /// Read the configuration, substitute environment variables and parse it
+    pub(crate) fn load(&self) -> Result<Config, String> {
+        let (config, unknown) = self.load_with_unknown()?;
+        for field in unknown {
+            warn!("Unknown configuration field: {}", field);
+        }
+        Ok(config)
+    }
+
+    /// Like `load`, also returning the fields of the configuration which
+    /// are not understood by the exporter
+    pub(crate) fn load_with_unknown(&self) -> Result<(Config, Vec<String>), String> {
+        let (config, unknown, problems) = self.load_checked()?;
+        match problems.into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok((config, unknown)),
+        }
+    }
+
+    /// Like `load_with_unknown`, but an invalid backup or module does not
+    /// stop the load: they are left out and the problem of each is returned.
+    /// Errors reading or parsing the configuration still stop it.
+    pub(crate) fn load_checked(&self) -> Result<(Config, Vec<String>, Vec<String>), String> {
+        let mut unknown = Vec::new();
+        let mut config = self.read_config(&mut unknown)?;
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+        config
+            .backups
+            .retain_mut(|backup| match backup.normalize(&mut names) {
+                Ok(()) => true,
+                Err(e) => {
+                    problems.push(e);
+                    false
+                }
+            });
+        config
+            .modules
+            .retain(|name, module| match module.normalize_module(name) {
+                Ok(()) => true,
+                Err(e) => {
+                    problems.push(e);
+                    false
+                }
+            });
+        Ok((config, unknown, problems))
+    }
+
+    fn read_config(&self, unknown: &mut Vec<String>) -> Result<Config, String> {
+        let Some(path) = &self.path else {
+            return match env::var(CONFIG_ENV) {
+                Ok(content) => {
+                    let mut value = self.parse(&content, ConfigFormat::Toml)?;
+                    let defaults = take_defaults(&mut value);
+                    apply_defaults(&mut value, &defaults);
+                    let mut config: Config = deserialize_config(value.clone(), CONFIG_ENV, unknown)
+                        .map_err(|e| format!("Invalid configuration: {}", e))?;
+                    config.effective = value;
+                    Ok(config)
+                }
+                Err(_) => Config::from_env(),
+            };
+        };
+
+        let files = config_files(Path::new(path))
+            .map_err(|e| format!("Unable to read the configuration file: {}", e))?;
+        // defaults of every file apply to the backups of all files
+        let mut values = Vec::new();
+        let mut defaults = Value::Object(Default::default());
+        for file in files {
+            let mut read = Vec::new();
+            let mut value = self.read(&file, &mut Vec::new(), &mut read)?;
+            merge(&mut defaults, take_defaults(&mut value));
+            values.push((file, value, read.len() == 1));
+        }
+        let has_defaults = defaults.as_object().is_some_and(|d| !d.is_empty());
+
+        let mut backups: Vec<Backup> = Vec::new();
+        let mut modules = BTreeMap::new();
+        let mut metric_prefix = None;
+        let mut metrics = MetricSelection::default();
+        let mut web = WebConfig::default();
+        let mut effective = Value::Object(Default::default());
+        let mut origins: HashMap<String, PathBuf> = HashMap::new();
+        for (file, mut value, standalone) in values {
+            apply_defaults(&mut value, &defaults);
+            merge(&mut effective, value.clone());
+            let config =
+                deserialize_config(value, &file.display().to_string(), unknown).map_err(|e| {
+                    // the file alone only has the same error without merged values
+                    let located = if standalone && !has_defaults {
+                        self.locate_error(&file)
+                    } else {
+                        None
+                    };
+                    located.unwrap_or_else(|| {
+                        format!("Invalid configuration {}: {}", file.display(), e)
+                    })
+                })?;
+            metric_prefix = config.metric_prefix.or(metric_prefix);
+            if config.metrics != MetricSelection::default() {
+                metrics = config.metrics;
+            }
+            if config.web != WebConfig::default() {
+                web = config.web;
+            }
+            modules.extend(config.modules);
+            for backup in config.backups {
+                // unnamed backups are named and checked once loaded
+                let name = Some(backup.name.clone()).filter(|n| !n.trim().is_empty());
+                if let Some(other) = name.and_then(|n| origins.insert(n, file.clone())) {
+                    return Err(format!(
+                        "Duplicate backup name {} in {} and {}",
+                        backup.name,
+                        other.display(),
+                        file.display()
+                    ));
+                }
+                backups.push(backup);
+            }
+        }
+        Ok(Config {
+            backups,
+            modules,
+            metric_prefix,
+            metrics,
+            web,
+            effective,
+        })
+    }
+
+    /// Every file read by `load`, including the included ones
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        let mut files = Vec::new();
+        for file in config_files(Path::new(path)).unwrap_or_default() {
+            // keep the files found so far if a file is invalid
+            let _ = self.read(&file, &mut Vec::new(), &mut files);
+        }
+        files
+    }
+
+    // Parse a file and merge the files of its `include` key into it
+    fn read(
+        &self,
+        file: &Path,
+        stack: &mut Vec<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<Value, String> {
+        files.push(file.to_path_buf());
+        let canonical = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+        if stack.contains(&canonical) {
+            return Err(format!("Circular include of {}", file.display()));
+        }
+
+        let content = fs::read_to_string(file).map_err(|e| {
+            format!(
+                "Unable to read the configuration file {}: {}",
+                file.display(),
+                e
+            )
+        })?;
+        let mut value = self
+            .parse(&content, ConfigFormat::from_path(file))
+            .map_err(|e| format!("{}: {}", file.display(), e))?;
+        let Some(include) = value.as_object_mut().and_then(|o| o.remove("include")) else {
+            return Ok(value);
+        };
+        let patterns: Vec<String> = serde_json::from_value(include)
+            .map_err(|e| format!("Invalid include in {}: {}", file.display(), e))?;
+
+        stack.push(canonical);
+        let dir = file.parent().unwrap_or(Path::new("."));
+        for pattern in patterns {
+            for included in resolve_include(dir, &pattern)? {
+                let other = self.read(&included, stack, files)?;
+                merge(&mut value, other);
+            }
+        }
+        stack.pop();
+        Ok(value)
+    }
+
+    // The merged configuration has no positions, so parse the file on its
+    // own to report the line of an invalid field
+    fn locate_error(&self, file: &Path) -> Option<String> {
+        let content = fs::read_to_string(file).ok()?;
+        let content = replace_with_env_vars(&content, self.strict_env).ok()?;
+        self.format
+            .unwrap_or(ConfigFormat::from_path(file))
+            .parse::<Config>(&content)
+            .err()
+            .map(|e| format!("{}: {}", file.display(), e))
+    }
+
+    fn parse(&self, content: &str, detected: ConfigFormat) -> Result<Value, String> {
+        let content = replace_with_env_vars(content, self.strict_env)?;
+        self.format.unwrap_or(detected).parse(&content)
+    }
+}
+
+// Paths of an include pattern, relative to the including file. Patterns
+// without wildcard must exist, glob patterns may match nothing.
+fn resolve_include(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = dir.join(pattern);
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path]);
+    }
+    let mut paths = glob::glob(&path.to_string_lossy())
+        .map_err(|e| format!("Invalid include pattern {}: {}", pattern, e))?
+        .filter_map(Result::ok)
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+// Deep merge `other` into `base`. Backups with the same name are merged,
+// so an included file can add fields like the password to a backup.
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                match (key.as_str(), base.get_mut(&key), value) {
+                    ("backup", Some(Value::Array(backups)), Value::Array(others)) => {
+                        for other in others {
+                            let existing = backups.iter_mut().find(|b| {
+                                b.get("name").is_some() && b.get("name") == other.get("name")
+                            });
+                            match existing {
+                                Some(backup) => merge(backup, other),
+                                None => backups.push(other),
+                            }
+                        }
+                    }
+                    (_, Some(existing), value) => merge(existing, value),
+                    (_, None, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+fn take_defaults(value: &mut Value) -> Value {
+    value
+        .as_object_mut()
+        .and_then(|o| o.remove("defaults"))
+        .unwrap_or_else(|| Value::Object(Default::default()))
+}
+
+// Every backup inherits the defaults, its own values win
+fn apply_defaults(value: &mut Value, defaults: &Value) {
+    if let Some(Value::Array(backups)) = value.get_mut("backup") {
+        for backup in backups {
+            let mut merged = defaults.clone();
+            merge(&mut merged, backup.take());
+            *backup = merged;
+        }
+    }
+}
+
+// Deserialize a parsed configuration, collecting the fields it ignores
+fn deserialize_config(
+    value: Value,
+    origin: &str,
+    unknown: &mut Vec<String>,
+) -> Result<Config, serde_json::Error> {
+    serde_ignored::deserialize(value, |path| {
+        unknown.push(format!("{} in {}", path, origin));
+    })
+}
+
+/// Files making up the configuration, a single file or the supported
+/// files of a directory in lexical order
+pub(crate) fn config_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        let supported = matches!(
+            file.extension().and_then(|e| e.to_str()),
+            Some("toml" | "yaml" | "yml" | "json")
+        );
+        if supported && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+impl Config {
+    // Build backups from `RUSTIC_EXPORTER_BACKUP_<N>_<FIELD>` variables
+    fn from_env() -> Result<Self, String> {
+        let mut backups: BTreeMap<usize, toml::Table> = BTreeMap::new();
+        for (key, value) in env::vars() {
+            let Some((index, field)) = key
+                .strip_prefix(BACKUP_ENV_PREFIX)
+                .and_then(|rest| rest.split_once('_'))
+            else {
+                continue;
+            };
+            let index: usize = index
+                .parse()
+                .map_err(|_| format!("Invalid backup index in {}", key))?;
+            let backup = backups.entry(index).or_insert_with(|| {
+                toml::Table::from_iter([("options".to_string(), toml::Table::new().into())])
+            });
+
+            let (table, field) = if let Some(option) = field.strip_prefix("OPTIONS_") {
+                ("options", option)
+            } else if let Some(label) = field.strip_prefix("LABELS_") {
+                ("labels", label)
+            } else {
+                let value = match field {
+                    "NAME" | "REPOSITORY" | "PASSWORD" | "PROFILE" | "GROUP"
+                    | "COLLECTION_MODE" => toml::Value::from(value),
+                    "RESTIC_ENV" | "ENABLED" | "KEEP_OPEN" | "SPREAD" => {
+                        toml::Value::from(value == "true")
+                    }
+                    "INTERVAL" => toml::Value::from(
+                        value
+                            .parse::<i64>()
+                            .map_err(|_| format!("Invalid interval in {}", key))?,
+                    ),
+                    _ => return Err(format!("Unsupported environment variable {}", key)),
+                };
+                backup.insert(field.to_lowercase(), value);
+                continue;
+            };
+            backup
+                .entry(table)
+                .or_insert_with(|| toml::Table::new().into())
+                .as_table_mut()
+                .unwrap()
+                .insert(field.to_lowercase(), value.into());
+        }
+
+        // a single backup from the restic environment
+        if backups.is_empty()
+            && (env::var("RESTIC_REPOSITORY").is_ok() || env::var("RESTIC_REPOSITORY_FILE").is_ok())
+        {
+            backups.insert(
+                0,
+                toml::Table::from_iter([
+                    ("name".to_string(), "restic".into()),
+                    ("restic_env".to_string(), true.into()),
+                ]),
+            );
+        }
+        if backups.is_empty() {
+            return Err(format!(
+                "No configuration file, {}, {}* or RESTIC_REPOSITORY environment variables",
+                CONFIG_ENV, BACKUP_ENV_PREFIX
+            ));
+        }
+        let effective = serde_json::json!({ "backup": backups.values().collect::<Vec<_>>() });
+        let backups = backups
+            .into_values()
+            .map(|b| b.try_into().map_err(|e| format!("Invalid backup: {}", e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Config {
+            backups,
+            modules: BTreeMap::new(),
+            metric_prefix: env::var(METRIC_PREFIX_ENV)
+                .ok()
+                .map(|p| parse_metric_prefix(&p))
+                .transpose()?,
+            metrics: MetricSelection::default(),
+            web: WebConfig::default(),
+            effective,
+        })
+    }
 }
 
 #[derive(Clone, Deserialize, Debug)]
 pub(crate) struct Backup {
+    /// Unique name, the `repo_name` label. Derived from the repository if omitted
+    #[serde(default)]
     pub(crate) name: String,
+    /// Disabled backups are kept in the configuration but not collected
+    #[serde(default = "enabled_by_default")]
+    pub(crate) enabled: bool,
+    /// Repository, may be taken from the profile instead
+    #[serde(default)]
     pub(crate) repository: String,
-    pub(crate) password: String,
-    pub(crate) options: HashMap<String, String>,
+    pub(crate) password: Option<String>,
+    /// Password fetched from Vault when the repository opens
+    pub(crate) password_vault: Option<VaultSecret>,
+    /// AWS Secrets Manager secret with the password, `id#key` for a key of a
+    /// JSON secret. Fetched when the repository opens.
+    pub(crate) password_aws_secret: Option<String>,
+    /// Directory of the `<name>_password` secret file, `/run/secrets` by default
+    pub(crate) secrets_dir: Option<String>,
+    #[serde(default)]
+    pub(crate) options: BTreeMap<String, String>,
+    /// Hot part of a repository split across hot and cold storage
+    pub(crate) repo_hot: Option<String>,
+    #[serde(default)]
+    pub(crate) options_hot: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) options_cold: BTreeMap<String, String>,
+    /// Typed backend sections, alternatives to `repository` and `options`
+    pub(crate) s3: Option<S3Backend>,
+    pub(crate) sftp: Option<SftpBackend>,
+    pub(crate) rest: Option<RestBackend>,
+    /// rustic profile name or path providing the repository settings
+    pub(crate) profile: Option<String>,
+    /// Take missing repository settings from restic environment variables
+    #[serde(default)]
+    pub(crate) restic_env: bool,
+    /// Collection interval in seconds, overrides `--interval`
+    pub(crate) interval: Option<u64>,
+    /// Schedule the collection after the first one at a random point of
+    /// the interval, spreading the cycles of the repositories
+    #[serde(default)]
+    pub(crate) spread: bool,
+    /// Random shift of each interval either way, e.g. "30s"
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) jitter: Option<Duration>,
+    #[serde(default)]
+    pub(crate) collection_mode: CollectionMode,
+    /// Age of the data of a cached backup collected again on scrape, the
+    /// interval if omitted
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) max_staleness: Option<Duration>,
+    /// Serve the stale data of a cached backup while collecting it in the
+    /// background, instead of waiting for the collection
+    #[serde(default)]
+    pub(crate) background_refresh: bool,
+    /// Keep the repository open between cycles, otherwise it is opened and
+    /// closed on every cycle
+    #[serde(default = "keep_open_by_default")]
+    pub(crate) keep_open: bool,
+    /// Limit of opening the repository and of each collection, e.g. "120s"
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) timeout: Option<Duration>,
+    /// Value of the `group` label of every metric of the repository, to
+    /// aggregate repositories by site or tier
+    pub(crate) group: Option<String>,
+    /// Static labels attached to every metric of the repository, values
+    /// with `{{ }}` are templates evaluated per snapshot
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+    /// Templated labels, moved out of `labels` when the configuration loads
+    #[serde(skip)]
+    pub(crate) label_templates: BTreeMap<String, LabelTemplate>,
+    #[serde(default)]
+    pub(crate) filter: SnapshotFilter,
+    /// Only export per snapshot metrics for the newest snapshot of each group
+    #[serde(default)]
+    pub(crate) latest_only: bool,
+    /// Only export per snapshot metrics for the most recent snapshots
+    pub(crate) max_snapshots: Option<usize>,
+    pub(crate) schedule: Option<Schedule>,
+    pub(crate) keep: Option<KeepOptions>,
+    /// rustic repository options like `no-cache` or `cache-dir`
+    pub(crate) repository_options: Option<RepositoryOptions>,
+    /// Optional labels of `rustic_snapshot_info`, all of them if omitted
+    pub(crate) snapshot_info_labels: Option<Vec<SnapshotInfoLabel>>,
+    /// Snapshot ids of 8 characters in labels, longer if they collide
+    #[serde(default)]
+    pub(crate) short_ids: bool,
+    /// One `rustic_snapshot_path_info` series per path instead of the
+    /// `paths` label of `rustic_snapshot_info`
+    #[serde(default)]
+    pub(crate) split_paths: bool,
+    /// One `rustic_snapshot_tag_info` series per tag instead of the `tags`
+    /// label of `rustic_snapshot_info`
+    #[serde(default)]
+    pub(crate) split_tags: bool,
+    /// Cap of series per metric family, the series of the oldest snapshots
+    /// are dropped beyond it
+    pub(crate) max_series: Option<usize>,
+    /// Rules rewriting or dropping the series of the repository
+    #[serde(default)]
+    pub(crate) relabel: Vec<RelabelRule>,
+    #[serde(default, deserialize_with = "optional_buckets")]
+    pub(crate) duration_buckets: Option<Vec<f64>>,
+    #[serde(default, deserialize_with = "optional_buckets")]
+    pub(crate) pack_size_buckets: Option<Vec<f64>>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn keep_open_by_default() -> bool {
+    true
+}
+
+/// When the snapshots of a backup are collected
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CollectionMode {
+    /// In the background, every interval
+    #[default]
+    Interval,
+    /// When /metrics is scraped, within the scrape timeout
+    OnScrape,
+    /// When /metrics is scraped and the last collection is older than
+    /// `max_staleness`
+    Cached,
+}
+
+/// Optional label of `rustic_snapshot_info`, the repository and snapshot ids
+/// are always present
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SnapshotInfoLabel {
+    Paths,
+    Hostname,
+    Username,
+    Tags,
+    ProgramVersion,
+}
+
+impl SnapshotInfoLabel {
+    pub(crate) const ALL: [SnapshotInfoLabel; 5] = [
+        SnapshotInfoLabel::Paths,
+        SnapshotInfoLabel::Hostname,
+        SnapshotInfoLabel::Username,
+        SnapshotInfoLabel::Tags,
+        SnapshotInfoLabel::ProgramVersion,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotInfoLabel::Paths => "paths",
+            SnapshotInfoLabel::Hostname => "hostname",
+            SnapshotInfoLabel::Username => "username",
+            SnapshotInfoLabel::Tags => "tags",
+            SnapshotInfoLabel::ProgramVersion => "program_version",
+        }
+    }
+}
+
+impl Backup {
+    /// rustic backend options of the backup, with `*-file` and `*_file`
+    /// options replaced by the content of the file under the option name
+    /// without suffix
+    pub(crate) fn backend_options(&self) -> Result<BackendOptions, String> {
+        let mut backend = BackendOptions::default()
+            .repository(&self.repository)
+            .options(read_option_files(&self.options)?)
+            .options_hot(read_option_files(&self.options_hot)?)
+            .options_cold(read_option_files(&self.options_cold)?);
+        if let Some(repo_hot) = &self.repo_hot {
+            backend = backend.repo_hot(repo_hot);
+        }
+        Ok(backend)
+    }
+
+    /// Name derived from the repository and its bucket or root, like
+    /// `s3-bucket-name` or `backup-path`
+    pub(crate) fn derived_name(&self) -> String {
+        let repository = self.repository.trim_start_matches("opendal:");
+        let location = ["bucket", "container", "root"]
+            .iter()
+            .filter_map(|key| self.options.get(*key).map(String::as_str));
+        std::iter::once(repository)
+            .chain(location)
+            .flat_map(|part| part.split(|c: char| !c.is_ascii_alphanumeric()))
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub(crate) fn snapshot_info_label_enabled(&self, label: SnapshotInfoLabel) -> bool {
+        // split lists have their own series
+        match label {
+            SnapshotInfoLabel::Paths if self.split_paths => return false,
+            SnapshotInfoLabel::Tags if self.split_tags => return false,
+            _ => {}
+        }
+        self.snapshot_info_labels
+            .as_ref()
+            .is_none_or(|labels| labels.contains(&label))
+    }
+
+    /// rustic repository options with the password of the backup. The
+    /// password options of `repository_options` are used if `password` is
+    /// not set, before falling back to the environment variables.
+    pub(crate) fn repository_options(&self) -> Result<RepositoryOptions, String> {
+        let opts = self.repository_options.clone().unwrap_or_default();
+        let configured = opts.password.is_some()
+            || opts.password_file.is_some()
+            || opts.password_command.is_some();
+        if self.password.is_none() && configured {
+            return Ok(opts);
+        }
+        match self.resolve_password() {
+            Some(password) => Ok(opts.password(password)),
+            None => Err(format!(
+                "No password configured, set password or RUSTIC_PASSWORD, repository: {}",
+                self.name
+            )),
+        }
+    }
+
+    /// Static labels of the repository, including the group
+    pub(crate) fn static_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = self.labels.clone();
+        if let Some(group) = &self.group {
+            labels.insert("group".to_string(), group.clone());
+        }
+        labels
+    }
+
+    // Apply the typed sections, profile, restic environment and templates
+    // of a backup and check it, the names of the backups checked so far
+    // must not be reused
+    fn normalize(&mut self, names: &mut HashSet<String>) -> Result<(), String> {
+        self.apply_backend()?;
+        self.apply_profile()?;
+        self.apply_restic_env()?;
+        self.apply_label_templates()?;
+        if self.repository.is_empty() {
+            return Err(format!("No repository configured for backup {}", self.name));
+        }
+        if self.name.trim().is_empty() {
+            self.name = self.derived_name();
+        }
+        self.validate_labels()?;
+        if self.group.is_some() && self.labels.contains_key("group") {
+            return Err(format!(
+                "group and labels.group are exclusive, backup: {}",
+                self.name
+            ));
+        }
+        let static_labels = self.static_labels();
+        for rule in &self.relabel {
+            rule.validate(&static_labels)
+                .map_err(|e| format!("Invalid relabel rule, backup: {}, {}", self.name, e))?;
+        }
+        // the name is the repo_name label, duplicates would merge series
+        if !names.insert(self.name.clone()) {
+            return Err(format!("Duplicate backup name {}", self.name));
+        }
+        Ok(())
+    }
+
+    // Apply the settings of a probe module like those of a backup. The
+    // repository comes with the probe, it replaces the one of a typed
+    // section or profile.
+    fn normalize_module(&mut self, name: &str) -> Result<(), String> {
+        if !self.repository.is_empty() {
+            return Err(format!(
+                "The repository of module {} is the target of the probe, remove it",
+                name
+            ));
+        }
+        self.apply_backend()?;
+        self.apply_profile()?;
+        self.apply_restic_password()?;
+        self.repository.clear();
+        self.apply_label_templates()?;
+        self.validate_labels()?;
+        let static_labels = self.static_labels();
+        for rule in &self.relabel {
+            rule.validate(&static_labels)
+                .map_err(|e| format!("Invalid relabel rule, module: {}, {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Check the labels of the backup have valid names, which are not set by
+    /// the exporter already
+    pub(crate) fn validate_labels(&self) -> Result<(), String> {
+        for name in self.labels.keys().chain(self.label_templates.keys()) {
+            if !is_valid_label_name(name) || name.starts_with("__") {
+                return Err(format!(
+                    "Invalid label name {}, backup: {}",
+                    name, self.name
+                ));
+            }
+            if RESERVED_LABELS.contains(&name.as_str()) {
+                return Err(format!(
+                    "Label {} is set by the exporter, backup: {}",
+                    name, self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the password comes from a secret store when the repository
+    /// opens, rather than from the configuration
+    pub(crate) fn password_from_secret_store(&self) -> bool {
+        self.password_vault.is_some() || self.password_aws_secret.is_some()
+    }
+
+    /// Password of the repository, falling back to `RUSTIC_PASSWORD_<NAME>`,
+    /// the `<name>_password` secret file and `RUSTIC_PASSWORD` if not
+    /// configured
+    pub(crate) fn resolve_password(&self) -> Option<String> {
+        if let Some(password) = &self.password {
+            return Some(password.clone());
+        }
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if let Ok(password) = env::var(format!("RUSTIC_PASSWORD_{}", name)) {
+            return Some(password);
+        }
+        // Docker and Kubernetes secrets convention
+        let secrets_dir = self.secrets_dir.as_deref().unwrap_or(DEFAULT_SECRETS_DIR);
+        let file = Path::new(secrets_dir).join(format!("{}_password", self.name));
+        if let Ok(password) = fs::read_to_string(&file) {
+            return Some(password.trim_end_matches(['\r', '\n']).to_string());
+        }
+        env::var("RUSTIC_PASSWORD").ok()
+    }
+}
+
+fn read_option_files(
+    options: &BTreeMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut resolved = HashMap::new();
+    for (key, value) in options {
+        let (key, value) = match key
+            .strip_suffix("-file")
+            .or_else(|| key.strip_suffix("_file"))
+        {
+            Some(option) => {
+                let secret = fs::read_to_string(value)
+                    .map_err(|e| format!("Unable to read {} of option {}: {}", value, key, e))?;
+                (option, secret.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => (key.as_str(), value.clone()),
+        };
+        if resolved.insert(key.to_string(), value).is_some() {
+            return Err(format!("Option {} is set both inline and from a file", key));
+        }
+    }
+    Ok(resolved)
+}
+
+// Substitute `${VAR}` and `${VAR:-default}` with environment variables.
+// Unset variables without default are replaced with an empty string,
+// or rejected in strict mode. Comment lines are left as they are.
+fn replace_with_env_vars(input: &str, strict: bool) -> Result<String, String> {
+    let re = Regex::new(r"\$\{([^}:]+)(?::-([^}]*))?\}").unwrap();
+    let mut missing = Vec::new();
+    let mut substitute = |line: &str| {
+        re.replace_all(line, |caps: &regex::Captures| {
+            let var_name = &caps[1];
+            match (env::var(var_name), caps.get(2)) {
+                (Ok(value), Some(default)) if value.is_empty() => default.as_str().to_string(),
+                (Ok(value), _) => value,
+                (Err(_), Some(default)) => default.as_str().to_string(),
+                (Err(_), None) => {
+                    missing.push(var_name.to_string());
+                    String::new()
+                }
+            }
+        })
+        .into_owned()
+    };
+    let output: String = input
+        .split_inclusive('\n')
+        .map(|line| {
+            if line.trim_start().starts_with('#') {
+                line.to_string()
+            } else {
+                substitute(line)
+            }
+        })
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(output);
+    }
+    if strict {
+        return Err(format!(
+            "Environment variables are not set: {}",
+            missing.join(", ")
+        ));
+    }
+    warn!(
+        "Environment variables are not set, using empty values: {}",
+        missing.join(", ")
+    );
+    Ok(output)
+}
+
+/// Copy of a configuration with the secrets masked: the values of fields
+/// named like passwords, secrets, tokens or keys, the basic auth hashes and
+/// the passwords of URLs
+pub(crate) fn redacted(value: &Value) -> Value {
+    const MASK: &str = "<redacted>";
+    let secret = |key: &str| {
+        let key = key.to_lowercase();
+        let file = key.ends_with("_file") || key.ends_with("-file");
+        !file
+            && ["password", "secret", "token", "key", "credential"]
+                .iter()
+                .any(|word| key.contains(word))
+    };
+    let url_password = Regex::new(r"(://[^:/@\s]+:)[^@/\s]+@").unwrap();
+    match value {
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(_) if secret(key) => Value::from(MASK),
+                    Value::Object(users) if key == "basic_auth_users" => users
+                        .keys()
+                        .map(|user| (user.clone(), Value::from(MASK)))
+                        .collect(),
+                    value => redacted(value),
+                };
+                (key.clone(), value)
+            })
+            .collect(),
+        Value::Array(values) => values.iter().map(redacted).collect(),
+        Value::String(string) => {
+            Value::from(url_password.replace_all(string, format!("${{1}}{}@", MASK)))
+        }
+        value => value.clone(),
+    }
+}
+
+/// Validate a metric name prefix, the `_` separator is added by the registry
+pub(crate) fn parse_metric_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim_end_matches('_');
+    let re = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+    if !prefix.is_empty() && !re.is_match(prefix) {
+        return Err(format!("Invalid metric prefix: {}", prefix));
+    }
+    Ok(prefix.to_string())
+}
+
+fn optional_metric_prefix<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|p| parse_metric_prefix(&p).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// Histogram buckets must be finite, positive and strictly increasing
+fn optional_buckets<'de, D>(deserializer: D) -> Result<Option<Vec<f64>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buckets = Option::<Vec<f64>>::deserialize(deserializer)?;
+    if let Some(buckets) = &buckets {
+        if let Some(bucket) = buckets.iter().find(|b| !b.is_finite() || **b <= 0.0) {
+            return Err(serde::de::Error::custom(format!(
+                "Histogram bucket {} is not a finite positive number",
+                bucket
+            )));
+        }
+        if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(serde::de::Error::custom(
+                "Histogram buckets are not strictly increasing",
+            ));
+        }
+    }
+    Ok(buckets)
+}
+
+// Parse human readable durations like "90s" or "26h"
+pub(crate) fn optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|d| humantime::parse_duration(&d).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup(toml: &str) -> Backup {
+        let mut backup: Backup = toml::from_str(toml).unwrap();
+        backup.apply_label_templates().unwrap();
+        backup
+    }
+
+    #[test]
+    fn comments_are_not_substituted() {
+        let input = "# password = \"${RUSTIC_EXPORTER_TEST_UNSET}\"\n  # ${RUSTIC_EXPORTER_TEST_UNSET}\nname = \"${RUSTIC_EXPORTER_TEST_UNSET:-local}\"\n";
+        let output = replace_with_env_vars(input, true).unwrap();
+        assert_eq!(
+            output,
+            "# password = \"${RUSTIC_EXPORTER_TEST_UNSET}\"\n  # ${RUSTIC_EXPORTER_TEST_UNSET}\nname = \"local\"\n"
+        );
+        assert!(
+            replace_with_env_vars("password = \"${RUSTIC_EXPORTER_TEST_UNSET}\"", true).is_err()
+        );
+    }
+
+    #[test]
+    fn counters_are_selected_by_exposed_name() {
+        let selection = MetricSelection {
+            enabled: None,
+            disabled: HashSet::from([
+                "rustic_collection_errors_total".to_string(),
+                "backup_snapshot_files_total".to_string(),
+            ]),
+        }
+        .without_prefix("backup");
+        assert!(!selection.is_enabled("collection_errors"));
+        // a gauge ending in _total keeps its name
+        assert!(!selection.is_enabled("snapshot_files_total"));
+        assert!(selection.is_enabled("snapshot_files"));
+    }
+
+    #[test]
+    fn buckets_are_validated() {
+        let buckets = |buckets: &str| {
+            toml::from_str::<Backup>(&format!("name = \"a\"\nduration_buckets = {}", buckets))
+                .map(|backup| backup.duration_buckets)
+        };
+        assert_eq!(buckets("[60, 300.5]").unwrap(), Some(vec![60.0, 300.5]));
+        for invalid in [
+            "[300, 60]",
+            "[60, 60]",
+            "[-1, 60]",
+            "[0, 60]",
+            "[nan]",
+            "[inf]",
+        ] {
+            assert!(buckets(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn labels_are_validated() {
+        let valid = backup("name = \"a\"\nlabels = { env = \"prod\", job = \"{{ hostname }}\" }");
+        assert!(valid.validate_labels().is_ok());
+        for labels in [
+            "{ \"1env\" = \"prod\" }",
+            "{ \"env-name\" = \"prod\" }",
+            "{ __env = \"prod\" }",
+            "{ repo_name = \"other\" }",
+            "{ hostname = \"{{ hostname | lower }}\" }",
+        ] {
+            let invalid = backup(&format!("name = \"a\"\nlabels = {}", labels));
+            assert!(invalid.validate_labels().is_err(), "{}", labels);
+        }
+    }
 }
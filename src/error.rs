@@ -0,0 +1,147 @@
+use regex::Regex;
+use std::fmt;
+use std::sync::LazyLock;
+
+/// Class of a collection error, used as the `kind` label of error metrics
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    Auth,
+    Network,
+    Lock,
+    Parse,
+    Other,
+}
+
+impl ErrorKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Auth => "auth",
+            ErrorKind::Network => "network",
+            ErrorKind::Lock => "lock",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    // rustic errors do not expose a stable kind, so classify by their message
+    fn classify(message: &str) -> Self {
+        if AUTH.is_match(message) {
+            ErrorKind::Auth
+        } else if LOCK.is_match(message) {
+            ErrorKind::Lock
+        } else if NETWORK.is_match(message) {
+            ErrorKind::Network
+        } else if PARSE.is_match(message) {
+            ErrorKind::Parse
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
+
+// Words are matched whole, so ids like `a401f…` or words like `block` are not
+// taken for a class. Status codes only count after `status` or `HTTP`.
+static AUTH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\b(passwords?|no suitable key|unauthori[sz]ed|forbidden|permission denied|access denied|credentials?)\b|\b(status(\s+code)?|http(/[0-9.]+)?)[\s:=]*(401|403)\b",
+    )
+    .unwrap()
+});
+static LOCK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(lock|locks|locked|locking)\b").unwrap());
+static NETWORK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\b(connect|connection|connections|connected|connecting|disconnected|timed out|timeout|dns|network|unreachable|reset by peer|broken pipe|tls)\b",
+    )
+    .unwrap()
+});
+static PARSE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\b(parse|parsed|parser|parsing|deseriali[sz]\w*|json|decode|decoding|invalid|unexpected)\b",
+    )
+    .unwrap()
+});
+
+/// Error raised while opening or reading a repository
+#[derive(Clone, Debug)]
+pub(crate) struct CollectionError {
+    pub(crate) kind: ErrorKind,
+    pub(crate) message: String,
+}
+
+impl CollectionError {
+    pub(crate) fn from_error(error: impl fmt::Display) -> Self {
+        let message = error.to_string();
+        Self {
+            kind: ErrorKind::classify(&message),
+            message,
+        }
+    }
+
+    /// Whether the error hints at a dead connection or expired credentials
+    /// of the repository handle, which a new handle may not have
+    pub(crate) fn is_connection_lost(&self) -> bool {
+        let message = self.message.to_lowercase();
+        // a timed out operation still runs on the old handle
+        if message.contains("timed out") {
+            return false;
+        }
+        self.kind == ErrorKind::Auth
+            || [
+                "disconnect",
+                "connection closed",
+                "connection reset",
+                "reset by peer",
+                "broken pipe",
+                "session",
+                "expired",
+            ]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+    }
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.kind.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(message: &str) -> ErrorKind {
+        CollectionError::from_error(message).kind
+    }
+
+    #[test]
+    fn ids_are_not_status_codes() {
+        assert_eq!(
+            kind("unable to read pack a401f3c2d9e8b7a6 from the backend"),
+            ErrorKind::Other
+        );
+        assert_eq!(kind("blob 9403ab12 not found in index"), ErrorKind::Other);
+        assert!(!CollectionError::from_error("snapshot 1403cafe is missing").is_connection_lost());
+    }
+
+    #[test]
+    fn status_codes_in_http_context() {
+        assert_eq!(kind("backend returned status: 401"), ErrorKind::Auth);
+        assert_eq!(kind("HTTP 403 for /config"), ErrorKind::Auth);
+        assert_eq!(kind("status code 403"), ErrorKind::Auth);
+        assert_eq!(kind("wrong password or no suitable key"), ErrorKind::Auth);
+    }
+
+    #[test]
+    fn whole_words() {
+        assert_eq!(
+            kind("repository is locked by another process"),
+            ErrorKind::Lock
+        );
+        assert_eq!(kind("unable to read block 12"), ErrorKind::Other);
+        assert_eq!(kind("clock skew detected"), ErrorKind::Other);
+        assert_eq!(kind("connection refused"), ErrorKind::Network);
+        assert_eq!(kind("passwordless backend"), ErrorKind::Other);
+    }
+}
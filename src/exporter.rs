@@ -0,0 +1,397 @@
+use crate::collector::{CollectorStatus, DisabledCollector, Limits, RusticCollector};
+use crate::config::{redacted, Backup, CollectionMode, Config, ConfigSource, MetricSelection};
+
+use chrono::{DateTime, Local};
+use prometheus_client::collector::Collector;
+use prometheus_client::registry::Registry;
+use rustic_core::repofile::SnapshotFile;
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinSet;
+use tracing::info;
+
+const DEFAULT_METRIC_PREFIX: &str = "rustic";
+
+/// Owns the running collectors and builds the registries served on /metrics
+pub(crate) struct Exporter {
+    source: ConfigSource,
+    interval: u64,
+    metric_prefix: Option<String>,
+    collectors: Mutex<HashMap<String, (Backup, RusticCollector)>>,
+    // shared by all collectors and probes
+    limits: Limits,
+    settings: Mutex<RegistrySettings>,
+}
+
+// Part of the configuration needed to build registries for scrapes and
+// probes
+#[derive(Default)]
+struct RegistrySettings {
+    prefix: String,
+    selection: Arc<MetricSelection>,
+    disabled: Vec<Backup>,
+    modules: BTreeMap<String, Backup>,
+    /// Effective configuration with masked secrets
+    config: Value,
+    // number of applied configurations and the time of the last one
+    generation: u64,
+    applied: Option<DateTime<Local>>,
+}
+
+/// Version of the metrics of some backups, changing when a collection cycle
+/// ends or a configuration is applied
+pub(crate) struct Revision {
+    pub(crate) etag: String,
+    pub(crate) modified: DateTime<Local>,
+}
+
+impl Exporter {
+    pub(crate) fn new(
+        source: ConfigSource,
+        interval: u64,
+        metric_prefix: Option<String>,
+        limits: Limits,
+    ) -> Self {
+        Self {
+            source,
+            interval,
+            metric_prefix,
+            collectors: Mutex::new(HashMap::new()),
+            limits,
+            settings: Mutex::new(RegistrySettings::default()),
+        }
+    }
+
+    pub(crate) fn config_path(&self) -> Option<&str> {
+        self.source.path.as_deref()
+    }
+
+    /// Files of the current configuration, including the included ones
+    pub(crate) fn config_files(&self) -> Vec<PathBuf> {
+        self.source.files()
+    }
+
+    /// Empty registry with the metric prefix of the configuration
+    pub(crate) fn new_registry(&self) -> Registry {
+        new_registry(&self.settings.lock().unwrap().prefix)
+    }
+
+    /// Configuration applied last, with masked secrets
+    pub(crate) fn config(&self) -> Value {
+        self.settings.lock().unwrap().config.clone()
+    }
+
+    /// Readiness of the repository of each enabled backup by name
+    pub(crate) fn readiness(&self) -> Vec<(String, bool)> {
+        let mut readiness: Vec<(String, bool)> = self
+            .collectors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, (_, collector))| (name.clone(), collector.is_ready()))
+            .collect();
+        readiness.sort();
+        readiness
+    }
+
+    /// Collection state of the enabled backups by name
+    pub(crate) fn statuses(&self) -> Vec<(String, CollectorStatus)> {
+        let mut statuses: Vec<(String, CollectorStatus)> = self
+            .collectors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, (_, collector))| (name.clone(), collector.status()))
+            .collect();
+        statuses.sort_by(|(a, _), (b, _)| a.cmp(b));
+        statuses
+    }
+
+    /// Cached snapshots of the enabled backups with an included name, as
+    /// selected by the snapshot filter of each backup
+    pub(crate) fn snapshots(
+        &self,
+        include: impl Fn(&str) -> bool,
+    ) -> Vec<(String, Vec<SnapshotFile>)> {
+        self.collectors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| include(name))
+            .map(|(name, (_, collector))| (name.clone(), collector.snapshots()))
+            .collect()
+    }
+
+    /// Start a collection cycle now for the enabled backups with an included
+    /// name, returns the names of the refreshed ones
+    pub(crate) fn refresh(&self, include: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut refreshed: Vec<String> = self
+            .collectors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, (_, collector))| include(name) && collector.refresh())
+            .map(|(name, _)| name.clone())
+            .collect();
+        refreshed.sort();
+        refreshed
+    }
+
+    /// Collect the backups with an included name which are collected on
+    /// scrape, and wait for the collections done inline
+    pub(crate) async fn collect_on_scrape(&self, include: impl Fn(&str) -> bool) {
+        let collectors: Vec<RusticCollector> = self
+            .collectors
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(backup, _)| {
+                backup.collection_mode != CollectionMode::Interval && include(&backup.name)
+            })
+            .map(|(_, collector)| collector.clone())
+            .collect();
+        let mut tasks = JoinSet::new();
+        for collector in collectors {
+            tasks.spawn(async move { collector.scraped().await });
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    /// Let the running collection cycles finish, then stop the collectors
+    /// and close their repositories
+    pub(crate) async fn shutdown(&self) {
+        let collectors: Vec<RusticCollector> = self
+            .collectors
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(_, collector)| collector.clone())
+            .collect();
+        let mut tasks = JoinSet::new();
+        for collector in collectors {
+            tasks.spawn(async move { collector.shutdown().await });
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    /// Open the target repository with the settings of the module and
+    /// collect its metrics once, into a registry of their own
+    pub(crate) async fn probe(&self, target: &str, module: &str) -> Result<Registry, String> {
+        let (prefix, selection, mut backup) = {
+            let settings = self.settings.lock().unwrap();
+            let backup = settings
+                .modules
+                .get(module)
+                .cloned()
+                .ok_or_else(|| format!("Unknown module {}", module))?;
+            (settings.prefix.clone(), settings.selection.clone(), backup)
+        };
+        backup.repository = target.to_string();
+        if backup.name.trim().is_empty() {
+            backup.name = backup.derived_name();
+        }
+        info!("Probing repository: {}", backup.name);
+        let collector = RusticCollector::collect_once(backup.clone(), self.limits.clone()).await;
+        let mut registry = new_registry(&prefix);
+        register(
+            &mut registry,
+            &backup,
+            Box::new(collector.with_selection(selection)),
+        );
+        Ok(registry)
+    }
+
+    /// Re-read the configuration file and apply it, the current
+    /// configuration keeps running if the file is invalid
+    pub(crate) fn reload(&self) -> Result<(), String> {
+        let config = self.source.load()?;
+        self.apply(config);
+        Ok(())
+    }
+
+    /// Start collectors of new or changed backups, stop the ones of removed
+    /// backups, and keep unchanged collectors with their opened repository
+    pub(crate) fn apply(&self, config: Config) {
+        let mut collectors = self.collectors.lock().unwrap();
+        let mut current = std::mem::take(&mut *collectors);
+
+        let mut disabled = Vec::new();
+        for backup in config.backups {
+            if !backup.enabled {
+                match current.remove(&backup.name) {
+                    Some((_, collector)) => {
+                        info!("Disabling repository: {}", backup.name);
+                        collector.stop();
+                    }
+                    None => info!("Skipping disabled repository: {}", backup.name),
+                }
+                disabled.push(backup);
+                continue;
+            }
+            let collector = match current.remove(&backup.name) {
+                // config structs hold foreign types without PartialEq, so
+                // compare their debug representation. Maps are ordered, so
+                // equal configurations print the same.
+                Some((old, collector)) if format!("{:?}", old) == format!("{:?}", backup) => {
+                    collector
+                }
+                Some((_, collector)) => {
+                    info!("Reloading repository: {}", backup.name);
+                    collector.stop();
+                    RusticCollector::new(backup.clone(), self.interval, self.limits.clone())
+                }
+                None => {
+                    info!("Registering repositroy: {}", backup.name);
+                    RusticCollector::new(backup.clone(), self.interval, self.limits.clone())
+                }
+            };
+            collectors.insert(backup.name.clone(), (backup, collector));
+        }
+        for (name, (_, collector)) in current {
+            info!("Removing repository: {}", name);
+            collector.stop();
+        }
+
+        drop(collectors);
+
+        let prefix = self
+            .metric_prefix
+            .clone()
+            .or(config.metric_prefix)
+            .unwrap_or_else(|| DEFAULT_METRIC_PREFIX.to_string());
+        let mut settings = self.settings.lock().unwrap();
+        *settings = RegistrySettings {
+            selection: Arc::new(config.metrics.without_prefix(&prefix)),
+            prefix,
+            disabled,
+            modules: config.modules,
+            config: redacted(&config.effective),
+            generation: settings.generation + 1,
+            applied: Some(Local::now()),
+        };
+    }
+
+    /// Revision of the metrics of the backups with an included name, `None`
+    /// if the metrics of a backup depend on the time of the scrape
+    pub(crate) fn revision(&self, include: impl Fn(&str) -> bool) -> Option<Revision> {
+        let collectors = self.collectors.lock().unwrap();
+        let settings = self.settings.lock().unwrap();
+        let mut generations = Vec::new();
+        let mut modified = settings.applied?;
+        for (name, (_, collector)) in collectors.iter() {
+            if include(name) {
+                let (generation, cycle_end) = collector.generation()?;
+                generations.push((name.as_str(), generation));
+                modified = modified.max(cycle_end.unwrap_or(modified));
+            }
+        }
+        generations.sort();
+        let mut hasher = DefaultHasher::new();
+        // the time of the configuration tells the tags of restarts apart
+        (settings.generation, settings.applied).hash(&mut hasher);
+        generations.hash(&mut hasher);
+        settings
+            .disabled
+            .iter()
+            .filter(|backup| include(&backup.name))
+            .for_each(|backup| backup.name.hash(&mut hasher));
+        Some(Revision {
+            // weak, the body may be compressed
+            etag: format!("W/\"{:016x}\"", hasher.finish()),
+            modified,
+        })
+    }
+
+    /// A registry for each backup with an included name, by name. The
+    /// registries cannot unregister collectors, so they are built for each
+    /// scrape.
+    pub(crate) fn registries(&self, include: impl Fn(&str) -> bool) -> Vec<Registry> {
+        let collectors = self.collectors.lock().unwrap();
+        let settings = self.settings.lock().unwrap();
+        let mut registries: Vec<(&str, Registry)> = Vec::new();
+        for (backup, collector) in collectors.values() {
+            if include(&backup.name) {
+                let mut registry = new_registry(&settings.prefix);
+                register(
+                    &mut registry,
+                    backup,
+                    Box::new(collector.with_selection(settings.selection.clone())),
+                );
+                registries.push((&backup.name, registry));
+            }
+        }
+        for backup in &settings.disabled {
+            if include(&backup.name) {
+                let mut registry = new_registry(&settings.prefix);
+                register(
+                    &mut registry,
+                    backup,
+                    Box::new(DisabledCollector::new(backup, settings.selection.clone())),
+                );
+                registries.push((&backup.name, registry));
+            }
+        }
+        registries.sort_by_key(|(name, _)| *name);
+        registries
+            .into_iter()
+            .map(|(_, registry)| registry)
+            .collect()
+    }
+}
+
+fn new_registry(prefix: &str) -> Registry {
+    if prefix.is_empty() {
+        Registry::default()
+    } else {
+        Registry::with_prefix(prefix)
+    }
+}
+
+// Register the collector of a backup with the static labels of the backup
+fn register(registry: &mut Registry, backup: &Backup, collector: Box<dyn Collector>) {
+    let labels = backup
+        .static_labels()
+        .into_iter()
+        .map(|(k, v)| (Cow::Owned(k), Cow::Owned(v)));
+    registry
+        .sub_registry_with_labels(labels)
+        .register_collector(collector);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[tokio::test]
+    async fn reload_keeps_unchanged_collectors() {
+        let path = env::temp_dir().join(format!("rustic-exporter-reload-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "[[backup]]\nname = \"local\"\nrepository = \"/nonexistent\"\npassword = \"x\"\n\
+             [backup.options]\na = \"1\"\nb = \"2\"\nc = \"3\"\nd = \"4\"\ne = \"5\"\n",
+        )
+        .unwrap();
+        let source = ConfigSource {
+            path: Some(path.display().to_string()),
+            format: None,
+            strict_env: false,
+        };
+        let exporter = Exporter::new(source, 300, None, Limits::new(1, 1));
+        let collector = || exporter.collectors.lock().unwrap()["local"].1.clone();
+        exporter.reload().unwrap();
+        let first = collector();
+        for _ in 0..10 {
+            exporter.reload().unwrap();
+            assert!(collector().is_same(&first));
+        }
+        first.stop();
+        fs::remove_file(&path).unwrap();
+    }
+}
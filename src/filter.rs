@@ -0,0 +1,115 @@
+use chrono::{DateTime, Local};
+use regex::Regex;
+use rustic_core::repofile::SnapshotFile;
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+use crate::config::optional_duration;
+
+/// Restricts which snapshots are turned into metrics
+#[derive(Clone, Deserialize, Debug, Default)]
+pub(crate) struct SnapshotFilter {
+    /// Only keep snapshots of these hostnames
+    #[serde(default)]
+    pub(crate) hosts: Vec<String>,
+    /// Only keep snapshots with these tags
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    /// Whether a snapshot needs any or all of `tags`
+    #[serde(default)]
+    pub(crate) tags_match: TagsMatch,
+    /// Only keep snapshots with these paths
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+    /// Whether a snapshot contains `paths` or has exactly these paths
+    #[serde(default)]
+    pub(crate) paths_match: PathsMatch,
+    /// Only keep snapshots with a hostname matching this regex
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub(crate) host_regex: Option<Regex>,
+    /// Only keep snapshots with a tag matching this regex
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub(crate) tag_regex: Option<Regex>,
+    /// Only keep snapshots with a path matching this regex
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub(crate) path_regex: Option<Regex>,
+    /// Drop snapshots of these hostnames, applied after the other filters
+    #[serde(default)]
+    pub(crate) exclude_hosts: Vec<String>,
+    /// Drop snapshots having any of these tags
+    #[serde(default)]
+    pub(crate) exclude_tags: Vec<String>,
+    /// Exclude older snapshots from per snapshot metrics, they still count
+    /// toward aggregated metrics
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) max_age: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TagsMatch {
+    #[default]
+    Any,
+    All,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PathsMatch {
+    #[default]
+    Contains,
+    Exact,
+}
+
+impl SnapshotFilter {
+    pub(crate) fn matches(&self, snapshot: &SnapshotFile) -> bool {
+        let host_matches = self.hosts.is_empty() || self.hosts.contains(&snapshot.hostname);
+        let tags_match = self.tags.is_empty()
+            || match self.tags_match {
+                TagsMatch::Any => self.tags.iter().any(|t| snapshot.tags.contains(t)),
+                TagsMatch::All => self.tags.iter().all(|t| snapshot.tags.contains(t)),
+            };
+        let paths_match = self.paths.is_empty()
+            || match self.paths_match {
+                PathsMatch::Contains => self.paths.iter().all(|p| snapshot.paths.contains(p)),
+                PathsMatch::Exact => {
+                    let mut paths: Vec<&String> = snapshot.paths.iter().collect();
+                    let mut expected: Vec<&String> = self.paths.iter().collect();
+                    paths.sort();
+                    paths.dedup();
+                    expected.sort();
+                    expected.dedup();
+                    paths == expected
+                }
+            };
+        let regex_matches = self
+            .host_regex
+            .as_ref()
+            .is_none_or(|re| re.is_match(&snapshot.hostname))
+            && self
+                .tag_regex
+                .as_ref()
+                .is_none_or(|re| snapshot.tags.iter().any(|t| re.is_match(t)))
+            && self
+                .path_regex
+                .as_ref()
+                .is_none_or(|re| snapshot.paths.iter().any(|p| re.is_match(p)));
+        let excluded = self.exclude_hosts.contains(&snapshot.hostname)
+            || self.exclude_tags.iter().any(|t| snapshot.tags.contains(t));
+        host_matches && tags_match && paths_match && regex_matches && !excluded
+    }
+
+    pub(crate) fn within_max_age(&self, snapshot: &SnapshotFile, now: DateTime<Local>) -> bool {
+        self.max_age
+            .is_none_or(|max_age| snapshot.time >= now - max_age)
+    }
+}
+
+fn optional_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|r| Regex::new(&r).map_err(serde::de::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,26 @@
+use crate::error::CollectionError;
+
+use prometheus_client::metrics::histogram::Histogram;
+use rustic_core::{repofile::IndexFile, NoProgressBars, OpenStatus, Repository};
+
+/// Walk all index files and record the size of every pack in use
+pub(crate) fn pack_size_histogram(
+    repository: &Repository<NoProgressBars, OpenStatus>,
+    buckets: &[f64],
+) -> Result<Histogram, CollectionError> {
+    let mut buckets = buckets.to_vec();
+    buckets.sort_by(|a, b| a.total_cmp(b));
+    let histogram = Histogram::new(buckets.into_iter());
+
+    let indexes = repository
+        .stream_files::<IndexFile>()
+        .map_err(CollectionError::from_error)?;
+    for index in indexes {
+        let (_, index) = index.map_err(CollectionError::from_error)?;
+        // packs_to_delete are about to be removed by prune, skip them
+        for pack in &index.packs {
+            histogram.observe(pack.pack_size() as f64);
+        }
+    }
+    Ok(histogram)
+}
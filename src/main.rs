@@ -1,47 +1,252 @@
+mod api;
+mod auth;
+mod aws;
+mod backend;
+mod check;
 mod cli;
 mod collector;
 mod config;
+mod error;
+mod exporter;
+mod filter;
+mod index;
+mod profile;
+mod relabel;
+mod restic;
+mod schedule;
+mod scrape;
+mod server;
+mod template;
+mod toolkit;
+mod vault;
 
-use config::Config;
+use config::ConfigSource;
+use exporter::Exporter;
+use scrape::Scraper;
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{Response, StatusCode},
-    response::IntoResponse,
-    routing::get,
-    Router,
+    extract::{Query, State},
+    http::{header, HeaderMap, Response, StatusCode},
+    middleware,
+    response::{Html, IntoResponse},
+    routing::{get, post},
+    Json, Router,
 };
 
+use chrono::{DateTime, Local};
 use clap::Parser;
 use core::panic;
-use prometheus_client::{encoding::text::encode, registry::Registry};
-use regex::Regex;
-use std::{
-    env, fs,
-    sync::{Arc, Mutex},
-};
+use prometheus_client::encoding::text::encode;
+use serde::Deserialize;
+use serde_json::json;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use tokio::signal;
-use tracing::{error, info};
+use tokio::sync::Notify;
+use tower_http::compression::CompressionLayer;
+use tracing::{error, info, warn};
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Seconds taken off the scrape timeout of Prometheus for the response
+const SCRAPE_TIMEOUT_OFFSET: f64 = 0.5;
+
+async fn metrics_handler(
+    State(scraper): State<Arc<Scraper>>,
+    Query(filter): Query<api::RepoFilter>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let revision = scraper.revision(&filter);
+    if let Some(revision) = revision.as_ref() {
+        if scrape::not_modified(&headers, revision) {
+            return (StatusCode::NOT_MODIFIED, scrape::validators(revision)).into_response();
+        }
+    }
+    let Some(_permit) = scraper.try_acquire() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            "Too many concurrent scrapes",
+        )
+            .into_response();
+    };
+    // leave time to send the response within the timeout of Prometheus
+    let timeout = headers
+        .get("X-Prometheus-Scrape-Timeout-Seconds")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .map(|seconds| Duration::from_secs_f64((seconds - SCRAPE_TIMEOUT_OFFSET).max(0.0)));
+    let (buffer, truncated) = scraper.scrape(&filter, timeout).await;
+    let mut response = Response::builder().status(StatusCode::OK);
+    // a truncated response is not the whole revision
+    if let Some(revision) = revision.filter(|_| !truncated) {
+        for (name, value) in scrape::validators(&revision) {
+            response = response.header(name, value);
+        }
+    }
+    response.body(Body::from(buffer)).unwrap().into_response()
+}
+
+#[derive(Deserialize)]
+struct ProbeParams {
+    target: String,
+    module: String,
+}
 
-async fn metrics_handler(State(state): State<Arc<Mutex<Registry>>>) -> impl IntoResponse {
-    let registry = state.lock().unwrap();
+// Metrics of a repository opened for this request, in the style of the
+// blackbox exporter
+async fn probe_handler(
+    State(exporter): State<Arc<Exporter>>,
+    Query(params): Query<ProbeParams>,
+) -> impl IntoResponse {
+    let registry = match exporter.probe(&params.target, &params.module).await {
+        Ok(registry) => registry,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
     let mut buffer = String::new();
     encode(&mut buffer, &registry).unwrap();
+    buffer.into_response()
+}
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::from(buffer))
-        .unwrap()
+// Landing page for manual checks, with the repositories and the endpoints
+async fn index_handler(State(exporter): State<Arc<Exporter>>) -> impl IntoResponse {
+    let repositories: String = exporter
+        .readiness()
+        .into_iter()
+        .map(|(name, _)| format!("<li>{}</li>", escape_html(&name)))
+        .collect();
+    Html(format!(
+        "<!DOCTYPE html>
+<html>
+<head><title>rustic-exporter</title></head>
+<body>
+<h1>rustic-exporter</h1>
+<p>Version {}</p>
+<h2>Repositories</h2>
+<ul>{}</ul>
+<h2>Endpoints</h2>
+<ul>
+<li><a href=\"metrics\">/metrics</a></li>
+<li><a href=\"healthz\">/healthz</a></li>
+<li><a href=\"readyz\">/readyz</a></li>
+</ul>
+</body>
+</html>
+",
+        env!("CARGO_PKG_VERSION"),
+        repositories
+    ))
 }
 
-fn replace_with_env_vars(input: &str) -> String {
-    let re = Regex::new(r"\$\{(.*)\}").unwrap();
-    re.replace_all(input, |caps: &regex::Captures| {
-        let var_name = caps[1].to_string();
-        env::var(var_name).unwrap_or_default()
-    })
-    .to_string()
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Effective configuration for debugging, with masked secrets
+async fn config_handler(State(exporter): State<Arc<Exporter>>) -> impl IntoResponse {
+    Json(exporter.config())
+}
+
+// State of the background collection of each repository, to find out why
+// a repository stopped updating
+async fn collectors_handler(State(exporter): State<Arc<Exporter>>) -> impl IntoResponse {
+    let time = |time: Option<DateTime<Local>>| time.map(|time| time.to_rfc3339());
+    let collectors: Vec<_> = exporter
+        .statuses()
+        .into_iter()
+        .map(|(name, status)| {
+            json!({
+                "name": name,
+                "ready": status.ready,
+                "running": status.running,
+                "collecting": status.collecting,
+                "cycle_start": time(status.cycle_start),
+                "cycle_end": time(status.cycle_end),
+                "next_cycle": time(status.next_cycle),
+                "last_error": status.last_error,
+                "snapshots": status.snapshots,
+            })
+        })
+        .collect();
+    Json(json!({ "collectors": collectors }))
+}
+
+// Versions of the build, for inventory tools
+async fn version_handler() -> impl IntoResponse {
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT"),
+        "rustic_core": env!("RUSTIC_CORE_VERSION"),
+        "rustic_backend": env!("RUSTIC_BACKEND_VERSION"),
+    }))
+}
+
+// Re-read and apply the configuration, like SIGHUP
+async fn reload_handler(State(exporter): State<Arc<Exporter>>) -> impl IntoResponse {
+    info!("Reload requested, reloading configuration");
+    match exporter.reload() {
+        Ok(()) => (StatusCode::OK, "OK".to_string()),
+        Err(e) => {
+            error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to reload configuration: {}", e),
+            )
+        }
+    }
+}
+
+// Collect the selected repositories now, after a backup run for example
+async fn refresh_handler(
+    State(exporter): State<Arc<Exporter>>,
+    Query(filter): Query<api::RepoFilter>,
+) -> impl IntoResponse {
+    let refreshed = exporter.refresh(|name| filter.includes(name));
+    let status = if refreshed.is_empty() {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::ACCEPTED
+    };
+    (status, Json(json!({ "refreshed": refreshed })))
+}
+
+async fn lifecycle_disabled_handler() -> impl IntoResponse {
+    (StatusCode::FORBIDDEN, "Lifecycle API is not enabled")
+}
+
+// Liveness, answering proves the server and the runtime are running
+async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
+// Readiness, ready once the repositories required by the policy are open
+async fn readyz_handler(exporter: Arc<Exporter>, policy: cli::Readiness) -> impl IntoResponse {
+    let readiness = exporter.readiness();
+    let ready = match policy {
+        cli::Readiness::All => readiness.iter().all(|(_, ready)| *ready),
+        // without backups there is no repository to wait for
+        cli::Readiness::Any => readiness.is_empty() || readiness.iter().any(|(_, ready)| *ready),
+        cli::Readiness::Immediately => true,
+    };
+    let repositories: Vec<_> = readiness
+        .into_iter()
+        .map(|(name, ready)| json!({ "name": name, "ready": ready }))
+        .collect();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({ "ready": ready, "repositories": repositories })),
+    )
 }
 
 #[tokio::main]
@@ -57,59 +262,223 @@ async fn main() {
         tracing_subscriber::fmt().with_env_filter(filter).init();
     }
 
-    let config_path = args.config_path;
-    let mut file_content = match fs::read_to_string(config_path.clone()) {
+    let source = ConfigSource {
+        path: args.config_path.clone(),
+        format: args.config_format,
+        strict_env: args.strict_env,
+    };
+    if let Some(cli::Command::CheckConfig) = args.command {
+        match check::check_config(&source) {
+            Ok(count) => println!("Configuration is valid, {} backups", count),
+            Err(problems) => {
+                for problem in problems {
+                    eprintln!("{}", problem);
+                }
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config = match source.load() {
         Ok(c) => c,
         Err(e) => {
-            error!("Unable to read the configuration file");
+            error!("Unable to load the configuration");
             panic!("Error: {}", e);
         }
     };
-    info!("Using configuration file: {}", config_path);
+    match &args.config_path {
+        Some(path) => info!("Using configuration file: {}", path),
+        None => info!("Using configuration from environment variables"),
+    }
+
+    let exporter = Arc::new(Exporter::new(
+        source,
+        args.interval,
+        args.metric_prefix.clone(),
+        collector::Limits::new(args.max_concurrent_opens, args.max_concurrent_collections),
+    ));
+    // the server settings are only read at startup
+    let web = match &args.web_config_file {
+        Some(file) => {
+            let replaced = server::WebConfig {
+                cors_origins: Vec::new(),
+                ..config.web.clone()
+            };
+            if replaced != server::WebConfig::default() {
+                warn!("The [web] section is ignored in favor of --web.config.file");
+            }
+            match toolkit::read_web_config_file(file) {
+                // the file has no CORS settings
+                Ok(web) => server::WebConfig {
+                    cors_origins: config.web.cors_origins.clone(),
+                    ..web
+                },
+                Err(e) => {
+                    error!("Cannot read the web configuration file");
+                    panic!("Error: {}", e);
+                }
+            }
+        }
+        None => config.web.clone(),
+    };
+    exporter.apply(config);
 
-    file_content = replace_with_env_vars(&file_content);
-    let config: Config = match toml::from_str(&file_content) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Invaid toml file");
-            panic!("Error: {}", e);
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(exporter.clone()));
+    if args.watch_config {
+        if exporter.config_path().is_some() {
+            tokio::spawn(watch_config(exporter.clone()));
+        } else {
+            warn!("No configuration file to watch");
+        }
+    }
+
+    let client_ca = args.tls_client_ca.as_ref().or(web.tls_client_ca.as_ref());
+    let tls = match (
+        args.tls_cert.as_ref().or(web.tls_cert.as_ref()),
+        args.tls_key.as_ref().or(web.tls_key.as_ref()),
+    ) {
+        (Some(cert), Some(key)) => {
+            match server::tls_acceptor(cert, key, client_ca.map(|ca| ca.as_str())) {
+                Ok(acceptor) => Some(acceptor),
+                Err(e) => {
+                    error!("Cannot set up TLS");
+                    panic!("Error: {}", e);
+                }
+            }
+        }
+        (None, None) => {
+            if client_ca.is_some() {
+                panic!("Error: client certificates need the TLS certificate and key");
+            }
+            None
         }
+        _ => panic!("Error: the TLS certificate and key must be set together"),
     };
 
-    let mut registry = Registry::default();
-    for backup in config.backups {
-        info!("Registering repositroy: {}", backup.name);
-        let collector = collector::RusticCollector::new(backup, args.interval);
-        registry.register_collector(Box::new(collector));
+    let mut addrs = args.listen.clone();
+    if addrs.is_empty() {
+        addrs.push(server::ListenAddr::Tcp(format!(
+            "{}:{}",
+            args.host, args.port
+        )));
     }
-    let addr = format!("{}:{}", args.host, args.port);
-    let listener = match tokio::net::TcpListener::bind(addr.clone()).await {
-        Ok(c) => c,
+    let mut listeners = Vec::new();
+    for addr in &addrs {
+        match server::Listener::bind(addr).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                error!("Cannot listen on {}", addr);
+                panic!("Error: {}", e);
+            }
+        }
+    }
+    let api = Router::new()
+        .route("/api/v1/snapshots", get(api::snapshots_handler))
+        .route("/api/v1/repositories", get(api::repositories_handler));
+    let api = match server::cors_layer(&web.cors_origins) {
+        Ok(Some(cors)) => api.layer(cors),
+        Ok(None) => api,
         Err(e) => {
-            error!("Cannot listen on {}", addr);
+            error!("Cannot set up CORS");
             panic!("Error: {}", e);
         }
     };
-    let registry_state = Arc::new(Mutex::new(registry));
-    let router = Router::new()
-        .route("/metrics", get(metrics_handler))
-        .with_state(registry_state);
-
-    info!("Start server on http://{addr}");
-    let server = axum::serve(listener, router);
-    let server_result = if cfg!(debug_assertions) {
-        server.await
+    let reload = if args.enable_lifecycle {
+        post(reload_handler)
     } else {
-        server.with_graceful_shutdown(shutdown_signal()).await
+        post(lifecycle_disabled_handler)
     };
-
-    match server_result {
-        Ok(_) => {}
+    let readiness = args.readiness;
+    let scraper = Arc::new(Scraper::new(exporter.clone(), args.max_requests));
+    let router = Router::new()
+        .route("/", get(index_handler))
+        // snapshot series make the payload large, compress it for the
+        // clients sending Accept-Encoding
+        .route(
+            "/metrics",
+            get(metrics_handler)
+                .with_state(scraper)
+                .layer(CompressionLayer::new()),
+        )
+        .route("/probe", get(probe_handler))
+        .merge(api)
+        .route("/config", get(config_handler))
+        .route("/debug/collectors", get(collectors_handler))
+        .route("/-/version", get(version_handler))
+        .route("/-/reload", reload)
+        .route("/-/refresh", post(refresh_handler))
+        .route("/healthz", get(healthz_handler))
+        .route(
+            "/readyz",
+            get(move |State(exporter)| readyz_handler(exporter, readiness)),
+        )
+        .with_state(exporter.clone());
+    let authenticator = match auth::Authenticator::new(&web) {
+        Ok(authenticator) => authenticator,
         Err(e) => {
-            error!("Failed to start server");
+            error!("Cannot set up authentication");
             panic!("Error: {}", e);
         }
     };
+    let router = match authenticator {
+        Some(authenticator) => {
+            if tls.is_none() {
+                warn!("Authentication is enabled without TLS, credentials are sent in clear text");
+            }
+            router.layer(middleware::from_fn_with_state(
+                Arc::new(authenticator),
+                auth::authenticate,
+            ))
+        }
+        None => {
+            if args.enable_lifecycle {
+                warn!("The lifecycle API is enabled without authentication, anyone reaching the server can reload the configuration");
+            }
+            router
+        }
+    };
+    let router = if args.access_log {
+        server::with_access_log(router)
+    } else {
+        router
+    };
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    for addr in &addrs {
+        match addr {
+            server::ListenAddr::Tcp(_) => info!("Start server on {scheme}://{addr}"),
+            server::ListenAddr::Unix(_) => info!("Start server on {addr}, serving {scheme}"),
+        }
+    }
+    let stop = Arc::new(Notify::new());
+    let serving = tokio::spawn(server::serve(listeners, router, tls, {
+        let stop = stop.clone();
+        async move { stop.notified().await }
+    }));
+
+    shutdown_signal().await;
+    info!(
+        "Shutting down, waiting up to {} for requests and collections",
+        humantime::format_duration(args.shutdown_timeout)
+    );
+    // stop accepting connections, then drain the requests and the
+    // collection cycles together
+    stop.notify_one();
+    let drained = tokio::time::timeout(args.shutdown_timeout, async {
+        let _ = tokio::join!(serving, exporter.shutdown());
+    })
+    .await;
+    // dropping the runtime would wait without limit for the blocking
+    // operations, like the ones hanging past their timeout, so exit here
+    if drained.is_err() {
+        warn!(
+            "Shutdown timed out, exiting and abandoning the requests and collections in progress"
+        );
+        std::process::exit(1);
+    }
+    std::process::exit(0);
 }
 
 async fn shutdown_signal() {
@@ -139,3 +508,61 @@ async fn shutdown_signal() {
         },
     }
 }
+
+// Reload the configuration file whenever SIGHUP is received
+#[cfg(unix)]
+async fn reload_on_hangup(exporter: Arc<Exporter>) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        info!("signal SIGHUP triggered, reloading configuration");
+        if let Err(e) = exporter.reload() {
+            error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            );
+        }
+    }
+}
+
+// Poll the configuration file and reload it once a change has settled,
+// polling also catches the symlink swaps of Kubernetes ConfigMaps
+async fn watch_config(exporter: Arc<Exporter>) {
+    let mut applied = read_config(&exporter).await;
+    let mut pending = None;
+    let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let content = read_config(&exporter).await;
+        if content == applied {
+            pending = None;
+            continue;
+        }
+        // debounce, the file has to stay the same for one poll interval
+        if pending.as_ref() != Some(&content) {
+            pending = Some(content);
+            continue;
+        }
+
+        info!("Configuration file changed, reloading configuration");
+        if let Err(e) = exporter.reload() {
+            error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            );
+        }
+        applied = content;
+        pending = None;
+    }
+}
+
+// Content of every file of the configuration, added or removed files of a
+// configuration directory or of an include pattern count as a change
+async fn read_config(exporter: &Exporter) -> Option<Vec<(PathBuf, Vec<u8>)>> {
+    let mut content = Vec::new();
+    for file in exporter.config_files() {
+        let data = tokio::fs::read(&file).await.ok()?;
+        content.push((file, data));
+    }
+    Some(content)
+}
@@ -0,0 +1,105 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use crate::config::Backup;
+
+// The parts of a rustic profile used by the exporter, other sections are ignored
+#[derive(Deserialize, Default)]
+struct Profile {
+    #[serde(default)]
+    repository: ProfileRepository,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct ProfileRepository {
+    repository: Option<String>,
+    repo_hot: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    #[serde(default)]
+    options: HashMap<String, toml::Value>,
+    #[serde(default)]
+    options_hot: HashMap<String, toml::Value>,
+    #[serde(default)]
+    options_cold: HashMap<String, toml::Value>,
+}
+
+// Resolve a profile name like rustic does, paths are used as is
+fn profile_path(profile: &str) -> PathBuf {
+    let path = Path::new(profile);
+    if path.components().count() > 1 || path.extension().is_some() {
+        return path.to_path_buf();
+    }
+    let file = format!("{}.toml", profile);
+    let mut dirs = Vec::new();
+    if let Ok(config_home) = env::var("XDG_CONFIG_HOME") {
+        dirs.push(PathBuf::from(config_home).join("rustic"));
+    }
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(PathBuf::from(home).join(".config").join("rustic"));
+    }
+    dirs.push(PathBuf::from("/etc/rustic"));
+    dirs.iter()
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(file))
+}
+
+impl Backup {
+    /// Fill the repositories, password and backend options of the backup from
+    /// its rustic profile, values set in the exporter configuration win
+    pub(crate) fn apply_profile(&mut self) -> Result<(), String> {
+        let Some(profile) = &self.profile else {
+            return Ok(());
+        };
+        let path = profile_path(profile);
+        let content = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "Unable to read the rustic profile {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let profile: Profile = toml::from_str(&content)
+            .map_err(|e| format!("Invalid rustic profile {}: {}", path.display(), e))?;
+        let repository = profile.repository;
+
+        if self.repository.is_empty() {
+            self.repository = repository.repository.unwrap_or_default();
+        }
+        if self.password.is_none() {
+            self.password = match (repository.password, repository.password_file) {
+                (Some(password), _) => Some(password),
+                (None, Some(file)) => Some(
+                    fs::read_to_string(&file)
+                        .map_err(|e| {
+                            format!("Unable to read the password file {}: {}", file.display(), e)
+                        })?
+                        .trim_end_matches(['\r', '\n'])
+                        .to_string(),
+                ),
+                (None, None) => None,
+            };
+        }
+        if self.repo_hot.is_none() {
+            self.repo_hot = repository.repo_hot;
+        }
+        merge_options(&mut self.options, repository.options);
+        merge_options(&mut self.options_hot, repository.options_hot);
+        merge_options(&mut self.options_cold, repository.options_cold);
+        Ok(())
+    }
+}
+
+fn merge_options(options: &mut BTreeMap<String, String>, profile: HashMap<String, toml::Value>) {
+    for (key, value) in profile {
+        let value = match value {
+            toml::Value::String(s) => s,
+            value => value.to_string(),
+        };
+        options.entry(key).or_insert(value);
+    }
+}
@@ -0,0 +1,343 @@
+use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+use prometheus_client::metrics::{MetricType, TypedMetric};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use tracing::warn;
+
+/// Label set of a series as name and value pairs
+pub(crate) type Labels = Vec<(String, String)>;
+
+static LABEL_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap());
+
+/// Whether the name is a valid Prometheus label name
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    LABEL_NAME.is_match(name)
+}
+
+/// Relabel rule applied to the series of a backup, like Prometheus'
+/// `metric_relabel_configs`
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct RelabelRule {
+    #[serde(default)]
+    source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    separator: String,
+    /// Anchored on both ends like in Prometheus
+    #[serde(default = "default_regex", deserialize_with = "anchored_regex")]
+    regex: Regex,
+    target_label: Option<String>,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+    #[serde(default)]
+    action: RelabelAction,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RelabelAction {
+    #[default]
+    Replace,
+    Keep,
+    Drop,
+    LabelMap,
+    LabelDrop,
+    LabelKeep,
+}
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_regex() -> Regex {
+    Regex::new("^(?:(.*))$").unwrap()
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+fn anchored_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let regex = String::deserialize(deserializer)?;
+    Regex::new(&format!("^(?:{})$", regex)).map_err(serde::de::Error::custom)
+}
+
+impl RelabelRule {
+    /// Check the rule has the fields its action needs and makes valid label
+    /// names, other than the static labels added after relabeling
+    pub(crate) fn validate(&self, static_labels: &BTreeMap<String, String>) -> Result<(), String> {
+        if self.action == RelabelAction::Replace && self.target_label.is_none() {
+            return Err("target_label is required by the replace action".to_string());
+        }
+        if let Some(target) = &self.target_label {
+            if !is_valid_label_name(target) {
+                return Err(format!("Invalid target_label {}", target));
+            }
+            if static_labels.contains_key(target) {
+                return Err(format!("target_label {} is a static label", target));
+            }
+        }
+        if self.action == RelabelAction::LabelMap {
+            // captures of label names are made of label name characters,
+            // any of them stands in for a capture
+            let references = Regex::new(r"\$(\w+|\{\w+\})").unwrap();
+            let target = references.replace_all(&self.replacement, "a");
+            if !is_valid_label_name(&target) {
+                return Err(format!(
+                    "The labelmap replacement {} does not make label names",
+                    self.replacement
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Apply the rule, `false` if the series is dropped
+    fn apply(&self, labels: &mut Labels) -> bool {
+        let value = self
+            .source_labels
+            .iter()
+            .map(|name| {
+                labels
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map_or("", |(_, v)| v.as_str())
+            })
+            .collect::<Vec<_>>()
+            .join(&self.separator);
+
+        match self.action {
+            RelabelAction::Replace => {
+                let Some(captures) = self.regex.captures(&value) else {
+                    return true;
+                };
+                let mut replaced = String::new();
+                captures.expand(&self.replacement, &mut replaced);
+                let target = self.target_label.as_deref().unwrap_or_default();
+                labels.retain(|(name, _)| name != target);
+                if !replaced.is_empty() {
+                    labels.push((target.to_string(), replaced));
+                }
+                true
+            }
+            RelabelAction::Keep => self.regex.is_match(&value),
+            RelabelAction::Drop => !self.regex.is_match(&value),
+            RelabelAction::LabelMap => {
+                let mapped: Labels = labels
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let captures = self.regex.captures(name)?;
+                        let mut target = String::new();
+                        captures.expand(&self.replacement, &mut target);
+                        // a capture may start with a digit
+                        is_valid_label_name(&target).then(|| (target, value.clone()))
+                    })
+                    .collect();
+                for (name, value) in mapped {
+                    labels.retain(|(n, _)| *n != name);
+                    labels.push((name, value));
+                }
+                true
+            }
+            RelabelAction::LabelDrop => {
+                labels.retain(|(name, _)| !self.regex.is_match(name));
+                true
+            }
+            RelabelAction::LabelKeep => {
+                labels.retain(|(name, _)| self.regex.is_match(name));
+                true
+            }
+        }
+    }
+}
+
+/// Apply the rules in order, `None` if the series is dropped
+pub(crate) fn relabel(rules: &[RelabelRule], labels: &Labels) -> Option<Labels> {
+    let mut labels = labels.clone();
+    for rule in rules {
+        if !rule.apply(&mut labels) {
+            return None;
+        }
+    }
+    Some(labels)
+}
+
+/// Settings shared by the families of a repository
+#[derive(Debug)]
+pub(crate) struct SeriesOptions {
+    rules: Vec<RelabelRule>,
+    max_series: Option<usize>,
+    dropped: AtomicU64,
+}
+
+impl SeriesOptions {
+    pub(crate) fn new(rules: Vec<RelabelRule>, max_series: Option<usize>) -> Self {
+        Self {
+            rules,
+            max_series,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Series dropped by the families because of `max_series`
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Metric family keyed by plain label sets, which are relabeled when the
+/// family is encoded
+pub(crate) struct Series<M> {
+    options: Arc<SeriesOptions>,
+    metrics: Mutex<HashMap<Labels, M>>,
+}
+
+impl<M: Clone> Series<M> {
+    pub(crate) fn new(options: &Arc<SeriesOptions>) -> Self {
+        Self {
+            options: options.clone(),
+            metrics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Metric of the label set, sharing its value with the family. Once the
+    /// family holds `max_series` series, new label sets get a metric which
+    /// is not exposed.
+    pub(crate) fn get_or_create<L>(&self, labels: &L) -> M
+    where
+        L: Clone + Into<Labels>,
+        M: Default,
+    {
+        let mut metrics = self.metrics.lock().unwrap();
+        let labels = labels.clone().into();
+        if let Some(metric) = metrics.get(&labels) {
+            return metric.clone();
+        }
+        if self
+            .options
+            .max_series
+            .is_some_and(|max| metrics.len() >= max)
+        {
+            self.options.dropped.fetch_add(1, Ordering::Relaxed);
+            return M::default();
+        }
+        metrics.entry(labels).or_default().clone()
+    }
+
+    /// Add a metric which cannot be created by default, like a histogram
+    pub(crate) fn insert<L>(&self, labels: &L, metric: M)
+    where
+        L: Clone + Into<Labels>,
+    {
+        self.metrics
+            .lock()
+            .unwrap()
+            .insert(labels.clone().into(), metric);
+    }
+}
+
+impl<M: EncodeMetric + TypedMetric> EncodeMetric for Series<M> {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        let metrics = self.metrics.lock().unwrap();
+        // in order of the original labels, so the same series is kept when
+        // rules make several series alike
+        let mut series: Vec<_> = metrics.iter().collect();
+        series.sort_by_key(|(labels, _)| *labels);
+        let mut encoded = HashSet::new();
+        let mut duplicates = Vec::new();
+        for (labels, metric) in series {
+            let Some(labels) = relabel(&self.options.rules, labels) else {
+                continue;
+            };
+            let mut key = labels.clone();
+            key.sort();
+            if !encoded.insert(key) {
+                duplicates.push(labels);
+                continue;
+            }
+            metric.encode(encoder.encode_family(&labels)?)?;
+        }
+        if let Some(labels) = duplicates.first() {
+            warn!(
+                "Relabeling gave {} series the labels of another series, they are dropped, like {:?}",
+                duplicates.len(),
+                labels
+            );
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+}
+
+impl<M> std::fmt::Debug for Series<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Series").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::metrics::gauge::Gauge;
+    use prometheus_client::registry::Registry;
+
+    fn rules(toml: &str) -> Vec<RelabelRule> {
+        #[derive(Deserialize)]
+        struct Rules {
+            relabel: Vec<RelabelRule>,
+        }
+        toml::from_str::<Rules>(toml).unwrap().relabel
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn series_made_alike_are_encoded_once() {
+        let options = Arc::new(SeriesOptions::new(
+            rules("[[relabel]]\naction = \"labeldrop\"\nregex = \"snapshot_id\""),
+            None,
+        ));
+        let series = Series::<Gauge>::new(&options);
+        for id in ["a", "b"] {
+            series
+                .get_or_create(&labels(&[("repo_name", "r"), ("snapshot_id", id)]))
+                .set(1);
+        }
+        let mut registry = Registry::default();
+        registry.register("snapshot", "Snapshot", series);
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        assert_eq!(text.matches("snapshot{repo_name=\"r\"} 1").count(), 1);
+    }
+
+    #[test]
+    fn invalid_label_names_are_rejected() {
+        let static_labels = BTreeMap::from([("env".to_string(), "prod".to_string())]);
+        let invalid = [
+            "[[relabel]]\nsource_labels = [\"repo_name\"]\ntarget_label = \"1repo\"",
+            "[[relabel]]\nsource_labels = [\"repo_name\"]\ntarget_label = \"env\"",
+            "[[relabel]]\naction = \"labelmap\"\nregex = \"repo_(.*)\"\nreplacement = \"repo-$1\"",
+        ];
+        for rule in invalid {
+            assert!(rules(rule)[0].validate(&static_labels).is_err(), "{}", rule);
+        }
+        let valid =
+            "[[relabel]]\naction = \"labelmap\"\nregex = \"repo_(.*)\"\nreplacement = \"r_$1\"";
+        assert!(rules(valid)[0].validate(&static_labels).is_ok());
+    }
+}
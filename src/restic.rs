@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::{env, fs};
+
+use crate::config::Backup;
+use tracing::warn;
+
+// restic backend credentials and the opendal options they map to
+const S3_ENV: &[(&str, &str)] = &[
+    ("AWS_ACCESS_KEY_ID", "access_key_id"),
+    ("AWS_SECRET_ACCESS_KEY", "secret_access_key"),
+    ("AWS_SESSION_TOKEN", "session_token"),
+    ("AWS_DEFAULT_REGION", "region"),
+    ("AWS_REGION", "region"),
+];
+const B2_ENV: &[(&str, &str)] = &[
+    ("B2_ACCOUNT_ID", "application_key_id"),
+    ("B2_ACCOUNT_KEY", "application_key"),
+];
+const AZURE_ENV: &[(&str, &str)] = &[
+    ("AZURE_ACCOUNT_NAME", "account_name"),
+    ("AZURE_ACCOUNT_KEY", "account_key"),
+    ("AZURE_ACCOUNT_SAS", "sas_token"),
+];
+const GCS_ENV: &[(&str, &str)] = &[("GOOGLE_APPLICATION_CREDENTIALS", "credential_path")];
+
+// Translate a restic repository into a rustic repository and backend options
+fn translate(repository: &str) -> Result<(String, HashMap<String, String>), String> {
+    let mut options = HashMap::new();
+    let (scheme, location) = repository.split_once(':').unwrap_or(("", repository));
+    let repository = match scheme {
+        "s3" => {
+            // s3:https://host/bucket/prefix or s3:host/bucket/prefix
+            let (protocol, rest) = match location.split_once("://") {
+                Some((protocol, rest)) => (protocol, rest),
+                None => ("https", location),
+            };
+            let mut parts = rest.splitn(3, '/');
+            let host = parts.next().unwrap_or_default();
+            let bucket = parts
+                .next()
+                .filter(|b| !b.is_empty())
+                .ok_or_else(|| format!("No bucket in restic repository {}", repository))?;
+            options.insert("endpoint".to_string(), format!("{}://{}", protocol, host));
+            options.insert("bucket".to_string(), bucket.to_string());
+            options.insert(
+                "root".to_string(),
+                format!("/{}", parts.next().unwrap_or("")),
+            );
+            add_env(&mut options, S3_ENV);
+            "opendal:s3".to_string()
+        }
+        "b2" | "azure" | "gs" => {
+            // b2:bucket:path, azure:container:/path, gs:bucket:/path
+            let (bucket, path) = location.split_once(':').unwrap_or((location, ""));
+            let (service, bucket_key, credentials) = match scheme {
+                "b2" => ("b2", "bucket", B2_ENV),
+                "azure" => ("azblob", "container", AZURE_ENV),
+                _ => ("gcs", "bucket", GCS_ENV),
+            };
+            options.insert(bucket_key.to_string(), bucket.to_string());
+            options.insert(
+                "root".to_string(),
+                format!("/{}", path.trim_start_matches('/')),
+            );
+            add_env(&mut options, credentials);
+            format!("opendal:{}", service)
+        }
+        "sftp" => {
+            // sftp:user@host:/path
+            let (endpoint, path) = location
+                .rsplit_once(':')
+                .ok_or_else(|| format!("No path in restic repository {}", repository))?;
+            let host = match endpoint.split_once('@') {
+                Some((user, host)) => {
+                    options.insert("user".to_string(), user.to_string());
+                    host
+                }
+                None => endpoint,
+            };
+            options.insert("endpoint".to_string(), host.to_string());
+            options.insert("root".to_string(), path.to_string());
+            "opendal:sftp".to_string()
+        }
+        "local" => location.to_string(),
+        // local paths, rest: and rclone: repositories are understood by rustic
+        _ => repository.to_string(),
+    };
+    Ok((repository, options))
+}
+
+fn add_env(options: &mut HashMap<String, String>, variables: &[(&str, &str)]) {
+    for (variable, option) in variables {
+        if let Ok(value) = env::var(variable) {
+            options.entry(option.to_string()).or_insert(value);
+        }
+    }
+}
+
+impl Backup {
+    /// Fill the repository, password and backend options of the backup from
+    /// restic environment variables, values set in the configuration win
+    pub(crate) fn apply_restic_env(&mut self) -> Result<(), String> {
+        if !self.restic_env {
+            return Ok(());
+        }
+        if self.repository.is_empty() {
+            let repository = match (
+                env::var("RESTIC_REPOSITORY"),
+                env::var("RESTIC_REPOSITORY_FILE"),
+            ) {
+                (Ok(repository), _) => repository,
+                (Err(_), Ok(file)) => read_trimmed(&file)?,
+                _ => {
+                    return Err(format!(
+                        "RESTIC_REPOSITORY is not set for backup {}",
+                        self.name
+                    ))
+                }
+            };
+            let (repository, options) = translate(&repository)?;
+            self.repository = repository;
+            for (key, value) in options {
+                self.options.entry(key).or_insert(value);
+            }
+        }
+        self.apply_restic_password()
+    }
+
+    /// Fill the password of the backup from `RESTIC_PASSWORD` or
+    /// `RESTIC_PASSWORD_FILE`, for probe modules which get the repository
+    /// from the probe
+    pub(crate) fn apply_restic_password(&mut self) -> Result<(), String> {
+        if !self.restic_env {
+            return Ok(());
+        }
+        if self.password.is_none() {
+            self.password = match (
+                env::var("RESTIC_PASSWORD"),
+                env::var("RESTIC_PASSWORD_FILE"),
+            ) {
+                (Ok(password), _) => Some(password),
+                (Err(_), Ok(file)) => Some(read_trimmed(&file)?),
+                _ => {
+                    if env::var("RESTIC_PASSWORD_COMMAND").is_ok() {
+                        warn!(
+                            "RESTIC_PASSWORD_COMMAND is not supported, backup: {}",
+                            self.name
+                        );
+                    }
+                    None
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+fn read_trimmed(file: &str) -> Result<String, String> {
+    fs::read_to_string(file)
+        .map(|content| content.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|e| format!("Unable to read {}: {}", file, e))
+}
@@ -0,0 +1,85 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Deserializer};
+use std::{str::FromStr, time::Duration};
+
+use crate::config::optional_duration;
+
+/// Expected backup schedule of a repository
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct Schedule {
+    /// Maximum age of the latest snapshot, e.g. "26h"
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) max_age: Option<Duration>,
+    /// Cron expression of the backup job, e.g. "0 3 * * *"
+    #[serde(default, deserialize_with = "optional_cron")]
+    pub(crate) cron: Option<cron::Schedule>,
+    /// Tolerance added to each cron occurrence before a backup is late
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub(crate) grace: Option<Duration>,
+}
+
+pub(crate) struct Compliance {
+    pub(crate) on_schedule: bool,
+    pub(crate) overdue_seconds: Option<f64>,
+}
+
+impl Schedule {
+    /// Evaluate the schedule against the latest snapshot time.
+    /// Returns `None` if the schedule declares no rule.
+    pub(crate) fn evaluate(
+        &self,
+        latest: Option<DateTime<Local>>,
+        now: DateTime<Local>,
+    ) -> Option<Compliance> {
+        let grace = self.grace.unwrap_or_default();
+        // the point in time at which a missing snapshot became late
+        let deadline = if let Some(cron) = &self.cron {
+            let expected = cron.after(&(now - grace)).next_back()?;
+            if latest.is_some_and(|l| l >= expected) {
+                return Some(Compliance {
+                    on_schedule: true,
+                    overdue_seconds: Some(0.0),
+                });
+            }
+            Some(expected + grace)
+        } else if let Some(max_age) = self.max_age {
+            latest.map(|l| l + max_age)
+        } else {
+            return None;
+        };
+
+        Some(match deadline {
+            Some(deadline) if deadline >= now => Compliance {
+                on_schedule: true,
+                overdue_seconds: Some(0.0),
+            },
+            Some(deadline) => Compliance {
+                on_schedule: false,
+                overdue_seconds: Some((now - deadline).num_milliseconds() as f64 / 1000.0),
+            },
+            // no snapshot at all
+            None => Compliance {
+                on_schedule: false,
+                overdue_seconds: None,
+            },
+        })
+    }
+}
+
+// accept the common 5-field form by prepending the seconds field
+fn optional_cron<'de, D>(deserializer: D) -> Result<Option<cron::Schedule>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(expression) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression
+    };
+    cron::Schedule::from_str(&expression)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,195 @@
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, HeaderName};
+use chrono::{DateTime, Utc};
+use prometheus_client::encoding::text::{encode_eof, encode_registry};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::api::RepoFilter;
+use crate::exporter::{Exporter, Revision};
+
+// Metrics encoded so far, a part for each backup
+#[derive(Clone, Default)]
+struct Encoding {
+    parts: Vec<Bytes>,
+    total: usize,
+    done: bool,
+}
+
+/// Serves scrapes of /metrics, with a limit of concurrent scrapes and a
+/// single encoding of the metrics shared by simultaneous scrapes
+pub(crate) struct Scraper {
+    exporter: Arc<Exporter>,
+    permits: Arc<Semaphore>,
+    // encoding of all backups in progress, scrapes arriving meanwhile wait
+    // for it
+    running: Arc<Mutex<Option<watch::Receiver<Encoding>>>>,
+}
+
+impl Scraper {
+    /// Scraper allowing `max_requests` concurrent scrapes, 0 for no limit
+    pub(crate) fn new(exporter: Arc<Exporter>, max_requests: usize) -> Self {
+        Self {
+            exporter,
+            permits: Arc::new(Semaphore::new(match max_requests {
+                0 => Semaphore::MAX_PERMITS,
+                max_requests => max_requests,
+            })),
+            running: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Permit to scrape, held until the response is built. `None` if the
+    /// limit of concurrent scrapes is reached.
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    /// Revision of the metrics of the backups selected by the filter
+    pub(crate) fn revision(&self, filter: &RepoFilter) -> Option<Revision> {
+        self.exporter.revision(|name| filter.includes(name))
+    }
+
+    /// Metrics of the backups selected by the filter in the text format,
+    /// and whether they were truncated. Past the timeout, only the backups
+    /// encoded so far are returned and `scrape_truncated` is set.
+    pub(crate) async fn scrape(
+        &self,
+        filter: &RepoFilter,
+        timeout: Option<Duration>,
+    ) -> (Bytes, bool) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        // backups collected on scrape are encoded with their data so far
+        // past the deadline
+        let collecting = self
+            .exporter
+            .collect_on_scrape(|name| filter.includes(name));
+        match deadline {
+            Some(deadline) => {
+                let _ = tokio::time::timeout_at(deadline, collecting).await;
+            }
+            None => collecting.await,
+        }
+        let mut receiver = if filter.is_empty() {
+            let mut running = self.running.lock().unwrap();
+            match &*running {
+                Some(receiver) => receiver.clone(),
+                None => {
+                    let receiver = self.start(|_| true, Some(self.running.clone()));
+                    *running = Some(receiver.clone());
+                    receiver
+                }
+            }
+        } else {
+            self.start(|name| filter.includes(name), None)
+        };
+        match deadline {
+            Some(deadline) => {
+                let _ = tokio::time::timeout_at(deadline, receiver.wait_for(|e| e.done)).await;
+            }
+            None => {
+                let _ = receiver.wait_for(|e| e.done).await;
+            }
+        }
+        let encoding = receiver.borrow().clone();
+
+        let truncated = !encoding.done;
+        if truncated {
+            warn!(
+                "Scrape truncated to answer within the scrape timeout, {} of {} backups encoded",
+                encoding.parts.len(),
+                encoding.total
+            );
+        }
+        let mut registry = self.exporter.new_registry();
+        let gauge = Gauge::<i64>::default();
+        gauge.set(truncated as i64);
+        registry.register(
+            "scrape_truncated",
+            "Whether the response was cut short to answer within the scrape timeout",
+            gauge,
+        );
+        let mut trailer = String::new();
+        encode_registry(&mut trailer, &registry)
+            .and_then(|()| encode_eof(&mut trailer))
+            .unwrap();
+        let mut body = encoding.parts.concat();
+        body.extend_from_slice(trailer.as_bytes());
+        (Bytes::from(body), truncated)
+    }
+
+    // Encode the registries of the included backups in the background, the
+    // shared encoding is cleared from `running` once done
+    fn start(
+        &self,
+        include: impl Fn(&str) -> bool,
+        running: Option<Arc<Mutex<Option<watch::Receiver<Encoding>>>>>,
+    ) -> watch::Receiver<Encoding> {
+        let registries = self.exporter.registries(include);
+        let (sender, receiver) = watch::channel(Encoding {
+            total: registries.len(),
+            ..Encoding::default()
+        });
+        tokio::task::spawn_blocking(move || {
+            encode_parts(registries, &sender);
+            // later scrapes encode again to see newer data
+            if let Some(running) = running {
+                *running.lock().unwrap() = None;
+            }
+            sender.send_modify(|e| e.done = true);
+        });
+        receiver
+    }
+}
+
+/// Headers validating cached responses of the revision
+pub(crate) fn validators(revision: &Revision) -> [(HeaderName, String); 2] {
+    [
+        (header::ETAG, revision.etag.clone()),
+        (
+            header::LAST_MODIFIED,
+            revision
+                .modified
+                .with_timezone(&Utc)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ),
+    ]
+}
+
+/// Whether the conditional headers of the request match the revision,
+/// `If-None-Match` takes precedence over `If-Modified-Since`
+pub(crate) fn not_modified(headers: &HeaderMap, revision: &Revision) -> bool {
+    // entity tags are compared weakly, the revision tag is weak anyway
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+        let tags = tags.to_str().unwrap_or_default();
+        return tags
+            .split(',')
+            .any(|tag| tag.trim() == "*" || weak(tag) == weak(&revision.etag));
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        .is_some_and(|since| revision.modified.timestamp() <= since.timestamp())
+}
+
+fn encode_parts(registries: Vec<Registry>, sender: &watch::Sender<Encoding>) {
+    for registry in registries {
+        // nobody waits for the rest anymore
+        if sender.is_closed() {
+            return;
+        }
+        let mut buffer = String::new();
+        match encode_registry(&mut buffer, &registry) {
+            Ok(()) => sender.send_modify(|e| e.parts.push(Bytes::from(buffer))),
+            Err(e) => warn!("Unable to encode the metrics of a backup: {}", e),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderValue, Method, Request, Response};
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use hyper_util::service::TowerToHyperService;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, fs, io};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, info, info_span, warn, Span};
+
+/// Settings of the HTTP server, the command line takes precedence
+#[derive(Clone, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebConfig {
+    /// PEM file of the server certificate chain, enables HTTPS
+    pub(crate) tls_cert: Option<String>,
+    /// PEM file of the private key of the server certificate
+    pub(crate) tls_key: Option<String>,
+    /// PEM file of the CAs verifying client certificates, requires clients
+    /// to present one
+    pub(crate) tls_client_ca: Option<String>,
+    /// Users allowed by HTTP basic auth with the bcrypt hash of their password
+    #[serde(default)]
+    pub(crate) basic_auth_users: HashMap<String, String>,
+    /// File with the bearer token required on requests,
+    /// `RUSTIC_EXPORTER_BEARER_TOKEN` if omitted
+    pub(crate) bearer_token_file: Option<String>,
+    /// Origins allowed to call the JSON API from a browser, `*` for any
+    #[serde(default)]
+    pub(crate) cors_origins: Vec<String>,
+}
+
+/// CORS layer allowing the origins to read the responses, `None` without
+/// origins
+pub(crate) fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>, String> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|e| format!("Invalid CORS origin {}: {}", origin, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET])
+            .allow_headers([header::AUTHORIZATION]),
+    ))
+}
+
+/// TLS acceptor serving the certificate chain and key of the PEM files,
+/// requiring client certificates signed by the client CAs if given
+pub(crate) fn tls_acceptor(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Unable to read the certificate {}: {}", cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Unable to read the private key {}: {}", key, e))?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(client_ca)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Unable to read the client CA {}: {}", client_ca, e))?
+            {
+                roots
+                    .add(ca)
+                    .map_err(|e| format!("Invalid client CA {}: {}", client_ca, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| format!("Invalid client CA {}: {}", client_ca, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Log the requests served by the router, the events are in the span of
+/// the request with its method, path and peer
+pub(crate) fn with_access_log(router: Router) -> Router {
+    router.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|request: &Request<Body>| {
+                let peer = request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(peer)| peer.to_string())
+                    .unwrap_or_default();
+                info_span!(
+                    "request",
+                    method = %request.method(),
+                    path = %request.uri().path(),
+                    peer = %peer,
+                )
+            })
+            .on_request(())
+            .on_response(|response: &Response<Body>, latency: Duration, _: &Span| {
+                info!(
+                    status = response.status().as_u16(),
+                    duration_ms = latency.as_secs_f64() * 1000.0,
+                    "Request served"
+                );
+            })
+            .on_failure(()),
+    )
+}
+
+/// Address to listen on, `host:port` or `unix:<path>` for a Unix domain
+/// socket
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        match addr.strip_prefix("unix:") {
+            Some("") => Err("Missing path of the Unix domain socket".to_string()),
+            Some(path) if cfg!(unix) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            Some(_) => Err("Unix domain sockets are not supported on this platform".to_string()),
+            None => Ok(ListenAddr::Tcp(addr.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => f.write_str(addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Bound socket accepting the connections of a listen address
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // a socket left by a previous run would fail the bind
+                if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok(Connection::Tcp(stream, peer))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Connection::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Serve the router on the listeners, over TLS if an acceptor is given,
+/// until the shutdown future completes and the open connections are done
+pub(crate) async fn serve(
+    listeners: Vec<Listener>,
+    router: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let (sender, mut connections) = mpsc::channel(1);
+    let accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(accept(listener, sender.clone())))
+        .collect();
+    drop(sender);
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let connection = tokio::select! {
+            Some(connection) = connections.recv() => connection,
+            _ = &mut shutdown => break,
+        };
+        let watcher = graceful.watcher();
+        match connection {
+            Connection::Tcp(stream, peer) => {
+                spawn_connection(stream, Some(peer), router.clone(), tls.clone(), watcher)
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                spawn_connection(stream, None, router.clone(), tls.clone(), watcher)
+            }
+        }
+    }
+    // dropping the listeners closes them
+    for task in accepting {
+        task.abort();
+    }
+    graceful.shutdown().await;
+}
+
+// Pass the connections of a listener to the serving loop
+async fn accept(listener: Listener, connections: mpsc::Sender<Connection>) {
+    loop {
+        match listener.accept().await {
+            Ok(connection) => {
+                if connections.send(connection).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("Failed to accept a connection: {}", e),
+        }
+    }
+}
+
+// Serve a connection in its own task, the peer is only known for TCP
+fn spawn_connection<S>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    router: Router,
+    tls: Option<TlsAcceptor>,
+    watcher: Watcher,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // handlers and the access log can read the peer as ConnectInfo
+    let router = match peer {
+        Some(peer) => router.layer(Extension(ConnectInfo(peer))),
+        None => router,
+    };
+    match tls {
+        Some(acceptor) => {
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, router, watcher).await,
+                    Err(e) => match peer {
+                        Some(peer) => debug!("TLS handshake with {} failed: {}", peer, e),
+                        None => debug!("TLS handshake failed: {}", e),
+                    },
+                }
+            });
+        }
+        None => {
+            tokio::spawn(serve_connection(stream, router, watcher));
+        }
+    }
+}
+
+async fn serve_connection<S>(stream: S, router: Router, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(router));
+    if let Err(e) = watcher.watch(connection.into_owned()).await {
+        debug!("Connection closed with an error: {}", e);
+    }
+}
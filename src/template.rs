@@ -0,0 +1,225 @@
+use regex::Regex;
+use rustic_core::repofile::SnapshotFile;
+
+use crate::config::Backup;
+
+/// Label value computed from the fields of each snapshot, written like
+/// `{{ tags | regex_capture('job=(\w+)') }}`
+#[derive(Clone, Debug)]
+pub(crate) struct LabelTemplate(Vec<Segment>);
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Text(String),
+    Expression(Field, Vec<Filter>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Id,
+    Hostname,
+    Username,
+    Label,
+    Paths,
+    Tags,
+    ProgramVersion,
+    Description,
+}
+
+#[derive(Clone, Debug)]
+enum Filter {
+    /// First capture group of the first matching value, or the whole match
+    RegexCapture(Regex),
+    Lower,
+    Upper,
+    Join(String),
+    Default(String),
+}
+
+impl LabelTemplate {
+    /// Whether the label value is a template rather than a static value
+    pub(crate) fn is_template(value: &str) -> bool {
+        value.contains("{{")
+    }
+
+    pub(crate) fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("Unclosed {{{{ in label template {}", template))?;
+            let (field, filters) = parse_expression(&rest[start + 2..start + end])
+                .map_err(|e| format!("{} in label template {}", e, template))?;
+            segments.push(Segment::Expression(field, filters));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Self(segments))
+    }
+
+    /// Evaluate the template for a snapshot, list fields are joined with `,`
+    pub(crate) fn render(&self, snapshot: &SnapshotFile) -> String {
+        let mut rendered = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Expression(field, filters) => {
+                    let values = filters
+                        .iter()
+                        .fold(field.values(snapshot), |values, filter| {
+                            filter.apply(values)
+                        });
+                    rendered.push_str(&values.join(","));
+                }
+            }
+        }
+        rendered
+    }
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "id" => Self::Id,
+            "hostname" => Self::Hostname,
+            "username" => Self::Username,
+            "label" => Self::Label,
+            "paths" => Self::Paths,
+            "tags" => Self::Tags,
+            "program_version" => Self::ProgramVersion,
+            "description" => Self::Description,
+            _ => return Err(format!("Unknown snapshot field {}", name)),
+        })
+    }
+
+    fn values(self, snapshot: &SnapshotFile) -> Vec<String> {
+        match self {
+            Self::Id => vec![snapshot.id.to_string()],
+            Self::Hostname => vec![snapshot.hostname.clone()],
+            Self::Username => vec![snapshot.username.clone()],
+            Self::Label => vec![snapshot.label.clone()],
+            Self::Paths => snapshot.paths.iter().cloned().collect(),
+            Self::Tags => snapshot.tags.iter().cloned().collect(),
+            Self::ProgramVersion => vec![snapshot.program_version.clone()],
+            Self::Description => snapshot.description.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Filter {
+    fn parse(name: &str, argument: Option<String>) -> Result<Self, String> {
+        let filter = match (name, argument) {
+            ("regex_capture", Some(regex)) => {
+                Self::RegexCapture(Regex::new(&regex).map_err(|e| e.to_string())?)
+            }
+            ("lower", None) => Self::Lower,
+            ("upper", None) => Self::Upper,
+            ("join", Some(separator)) => Self::Join(separator),
+            ("default", Some(value)) => Self::Default(value),
+            ("regex_capture" | "join" | "default", None) => {
+                return Err(format!("Filter {} needs an argument", name))
+            }
+            ("lower" | "upper", Some(_)) => {
+                return Err(format!("Filter {} takes no argument", name))
+            }
+            _ => return Err(format!("Unknown filter {}", name)),
+        };
+        Ok(filter)
+    }
+
+    fn apply(&self, values: Vec<String>) -> Vec<String> {
+        match self {
+            Self::RegexCapture(regex) => values
+                .iter()
+                .find_map(|value| {
+                    let captures = regex.captures(value)?;
+                    captures.get(1).or_else(|| captures.get(0))
+                })
+                .map(|m| m.as_str().to_string())
+                .into_iter()
+                .collect(),
+            Self::Lower => values.iter().map(|v| v.to_lowercase()).collect(),
+            Self::Upper => values.iter().map(|v| v.to_uppercase()).collect(),
+            Self::Join(separator) => vec![values.join(separator)],
+            Self::Default(default) => {
+                if values.iter().all(String::is_empty) {
+                    vec![default.clone()]
+                } else {
+                    values
+                }
+            }
+        }
+    }
+}
+
+// Parse `field | filter | filter('argument')`, arguments are quoted with ' or "
+fn parse_expression(expression: &str) -> Result<(Field, Vec<Filter>), String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quote = None;
+    for c in expression.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                part.push(c);
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                part.push(c);
+            }
+            (None, '|') => parts.push(std::mem::take(&mut part)),
+            _ => part.push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("Unclosed quote".to_string());
+    }
+    parts.push(part);
+
+    let mut parts = parts.iter().map(|p| p.trim());
+    let field = Field::parse(parts.next().unwrap_or_default())?;
+    let filters = parts
+        .map(|part| match part.split_once('(') {
+            Some((name, argument)) => {
+                let argument = argument
+                    .strip_suffix(')')
+                    .map(str::trim)
+                    .and_then(|a| {
+                        a.strip_prefix('\'')
+                            .and_then(|a| a.strip_suffix('\''))
+                            .or_else(|| a.strip_prefix('"').and_then(|a| a.strip_suffix('"')))
+                    })
+                    .ok_or_else(|| format!("Invalid argument of filter {}", name.trim()))?;
+                Filter::parse(name.trim(), Some(argument.to_string()))
+            }
+            None => Filter::parse(part, None),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((field, filters))
+}
+
+impl Backup {
+    /// Move the templated values out of the static labels of the backup,
+    /// they are evaluated per snapshot
+    pub(crate) fn apply_label_templates(&mut self) -> Result<(), String> {
+        let templates: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|(_, value)| LabelTemplate::is_template(value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in templates {
+            let value = self.labels.remove(&name).unwrap_or_default();
+            let template = LabelTemplate::parse(&value)
+                .map_err(|e| format!("{}, backup: {}", e, self.name))?;
+            self.label_templates.insert(name, template);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+use crate::server::WebConfig;
+
+// Web configuration file of the Prometheus exporter-toolkit, see
+// https://prometheus.io/docs/prometheus/latest/configuration/https/
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ToolkitConfig {
+    tls_server_config: Option<TlsServerConfig>,
+    http_server_config: Option<Value>,
+    #[serde(default)]
+    basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsServerConfig {
+    cert_file: Option<String>,
+    key_file: Option<String>,
+    client_auth_type: Option<String>,
+    client_ca_file: Option<String>,
+    // settings of the Go TLS stack, rustls picks its own
+    min_version: Option<Value>,
+    max_version: Option<Value>,
+    cipher_suites: Option<Value>,
+    curve_preferences: Option<Value>,
+    prefer_server_cipher_suites: Option<Value>,
+    client_allowed_sans: Option<Value>,
+}
+
+/// Web configuration read from an exporter-toolkit web configuration file.
+/// Relative paths are relative to the directory of the file.
+pub(crate) fn read_web_config_file(file: &str) -> Result<WebConfig, String> {
+    let content = fs::read_to_string(file)
+        .map_err(|e| format!("Unable to read the web configuration file {}: {}", file, e))?;
+    let config: ToolkitConfig = serde_yaml::from_str(&content)
+        .map_err(|e| format!("Invalid web configuration file {}: {}", file, e))?;
+    let dir = Path::new(file).parent().unwrap_or(Path::new("."));
+    let resolve = |path: Option<String>| {
+        path.filter(|p| !p.is_empty())
+            .map(|p| dir.join(p).to_string_lossy().into_owned())
+    };
+
+    if config.http_server_config.is_some() {
+        warn!("http_server_config of the web configuration file is not supported, ignoring it");
+    }
+    let mut web = WebConfig {
+        basic_auth_users: config.basic_auth_users,
+        ..WebConfig::default()
+    };
+    let Some(tls) = config.tls_server_config else {
+        return Ok(web);
+    };
+    let ignored = [
+        ("min_version", tls.min_version.is_some()),
+        ("max_version", tls.max_version.is_some()),
+        ("cipher_suites", tls.cipher_suites.is_some()),
+        ("curve_preferences", tls.curve_preferences.is_some()),
+        (
+            "prefer_server_cipher_suites",
+            tls.prefer_server_cipher_suites.is_some(),
+        ),
+        ("client_allowed_sans", tls.client_allowed_sans.is_some()),
+    ];
+    for (name, _) in ignored.iter().filter(|(_, set)| *set) {
+        warn!(
+            "{} of the web configuration file is not supported, ignoring it",
+            name
+        );
+    }
+    web.tls_cert = resolve(tls.cert_file);
+    web.tls_key = resolve(tls.key_file);
+    match tls.client_auth_type.as_deref() {
+        None | Some("") | Some("NoClientCert") => {}
+        Some("RequireAndVerifyClientCert") => {
+            web.tls_client_ca = Some(resolve(tls.client_ca_file).ok_or(
+                "client_ca_file is required by client_auth_type RequireAndVerifyClientCert",
+            )?);
+        }
+        Some(other) => {
+            return Err(format!(
+                "Unsupported client_auth_type {}, use NoClientCert or RequireAndVerifyClientCert",
+                other
+            ))
+        }
+    }
+    Ok(web)
+}
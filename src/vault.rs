@@ -0,0 +1,174 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use std::{env, fs};
+
+use crate::config::Backup;
+
+const KUBERNETES_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Prefix of backend option values read from Vault, like
+/// `vault:secret/data/s3#secret_access_key`
+const OPTION_PREFIX: &str = "vault:";
+
+/// Repository password kept in HashiCorp Vault
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VaultSecret {
+    /// Address of the Vault server, `VAULT_ADDR` if omitted
+    addr: Option<String>,
+    /// Path of the secret, like `secret/data/backup` for a KV version 2 engine
+    path: String,
+    #[serde(default = "default_field")]
+    field: String,
+    #[serde(default)]
+    auth: VaultAuth,
+    /// Role of the Kubernetes auth method
+    role: Option<String>,
+    /// Mount path of the Kubernetes auth method
+    #[serde(default = "default_auth_mount")]
+    auth_mount: String,
+    /// File with the token of the token auth method, `VAULT_TOKEN` if omitted
+    token_file: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum VaultAuth {
+    #[default]
+    Token,
+    Kubernetes,
+}
+
+fn default_field() -> String {
+    "password".to_string()
+}
+
+fn default_auth_mount() -> String {
+    "kubernetes".to_string()
+}
+
+// Authenticated connection to a Vault server
+struct VaultClient {
+    client: reqwest::Client,
+    addr: String,
+    token: String,
+}
+
+impl VaultClient {
+    async fn login(secret: &VaultSecret) -> Result<Self, String> {
+        let addr = match &secret.addr {
+            Some(addr) => addr.clone(),
+            None => {
+                env::var("VAULT_ADDR").map_err(|_| "No Vault address, set addr or VAULT_ADDR")?
+            }
+        };
+        let addr = addr.trim_end_matches('/').to_string();
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let token = match secret.auth {
+            VaultAuth::Token => match &secret.token_file {
+                Some(file) => read_trimmed(file)?,
+                None => env::var("VAULT_TOKEN")
+                    .map_err(|_| "No Vault token, set token_file or VAULT_TOKEN")?,
+            },
+            VaultAuth::Kubernetes => {
+                let role = secret
+                    .role
+                    .as_ref()
+                    .ok_or("role is required by the kubernetes auth method")?;
+                let jwt = read_trimmed(KUBERNETES_TOKEN)?;
+                let response: Value = client
+                    .post(format!("{}/v1/auth/{}/login", addr, secret.auth_mount))
+                    .json(&serde_json::json!({ "role": role, "jwt": jwt }))
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| format!("Vault login failed: {}", e))?
+                    .json()
+                    .await
+                    .map_err(|e| format!("Invalid Vault login response: {}", e))?;
+                response["auth"]["client_token"]
+                    .as_str()
+                    .ok_or("No client token in the Vault login response")?
+                    .to_string()
+            }
+        };
+        Ok(Self {
+            client,
+            addr,
+            token,
+        })
+    }
+
+    async fn read(&self, path: &str, field: &str) -> Result<String, String> {
+        let response: Value = self
+            .client
+            .get(format!("{}/v1/{}", self.addr, path.trim_start_matches('/')))
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Unable to read Vault secret {}: {}", path, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid Vault response for {}: {}", path, e))?;
+        // KV version 2 nests the secret in a second data object
+        let data = &response["data"];
+        data["data"][field]
+            .as_str()
+            .or_else(|| data[field].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| format!("No field {} in Vault secret {}", field, path))
+    }
+}
+
+impl Backup {
+    /// Fetch the password and the `vault:` backend options of the backup
+    /// from Vault
+    pub(crate) async fn apply_vault(&mut self) -> Result<(), String> {
+        let references = self
+            .options
+            .values()
+            .chain(self.options_hot.values())
+            .chain(self.options_cold.values())
+            .any(|value| value.starts_with(OPTION_PREFIX));
+        let Some(secret) = &self.password_vault else {
+            if references {
+                return Err(format!(
+                    "vault: options need password_vault for the connection, backup: {}",
+                    self.name
+                ));
+            }
+            return Ok(());
+        };
+
+        let client = VaultClient::login(secret).await?;
+        self.password = Some(client.read(&secret.path, &secret.field).await?);
+        for options in [
+            &mut self.options,
+            &mut self.options_hot,
+            &mut self.options_cold,
+        ] {
+            for value in options.values_mut() {
+                let Some(reference) = value.strip_prefix(OPTION_PREFIX) else {
+                    continue;
+                };
+                let (path, field) = reference.split_once('#').ok_or_else(|| {
+                    format!("Vault option {} is not written as path#field", reference)
+                })?;
+                *value = client.read(path, field).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_trimmed(file: &str) -> Result<String, String> {
+    fs::read_to_string(file)
+        .map(|content| content.trim().to_string())
+        .map_err(|e| format!("Unable to read {}: {}", file, e))
+}